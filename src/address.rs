@@ -0,0 +1,134 @@
+use std::{fmt, str::FromStr};
+
+use once_cell::sync::Lazy;
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::custom_error::CustomError;
+
+// Shared secp256k1 context, building one is expensive so we only do it once
+pub(crate) static SECP: Lazy<Secp256k1<All>> = Lazy::new(Secp256k1::new);
+
+// Version byte prepended to the payload before Base58Check encoding
+const ADDRESS_VERSION: u8 = 0x00;
+const PAYLOAD_LEN: usize = 20;
+const CHECKSUM_LEN: usize = 4;
+
+// An account address: the first 20 bytes of SHA-256 of a compressed secp256k1 public key.
+// Rendered as Base58Check (version byte + payload + 4 byte double SHA-256 checksum) so typos are caught on parse.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address([u8; PAYLOAD_LEN]);
+
+impl Address {
+    // Placeholder sender of coinbase transactions, no key realistically hashes to it
+    pub const NULL: Address = Address([0; PAYLOAD_LEN]);
+
+    pub fn from_public_key(public_key: &PublicKey) -> Self {
+        let hash = Sha256::digest(&public_key.serialize());
+        let mut payload = [0; PAYLOAD_LEN];
+        payload.copy_from_slice(&hash[..PAYLOAD_LEN]);
+        Address(payload)
+    }
+
+    pub fn from_secret_key(secret_key: &SecretKey) -> Self {
+        Address::from_public_key(&PublicKey::from_secret_key(&SECP, secret_key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PAYLOAD_LEN] {
+        &self.0
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = Sha256::digest(&Sha256::digest(data));
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&hash[..CHECKSUM_LEN]);
+    checksum
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = Vec::with_capacity(1 + PAYLOAD_LEN + CHECKSUM_LEN);
+        data.push(ADDRESS_VERSION);
+        data.extend_from_slice(&self.0);
+        let checksum = checksum(&data);
+        data.extend_from_slice(&checksum);
+        write!(f, "{}", bs58::encode(data).into_string())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Address({})", self)
+    }
+}
+
+impl FromStr for Address {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let data = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| CustomError::new("Address is not valid Base58"))?;
+        if data.len() != 1 + PAYLOAD_LEN + CHECKSUM_LEN {
+            return Err(CustomError::new("Address has the wrong length"));
+        }
+        let (body, check) = data.split_at(1 + PAYLOAD_LEN);
+        if checksum(body) != check {
+            return Err(CustomError::new("Address checksum mismatch"));
+        }
+        if body[0] != ADDRESS_VERSION {
+            return Err(CustomError::new("Unknown address version"));
+        }
+        let mut payload = [0; PAYLOAD_LEN];
+        payload.copy_from_slice(&body[1..]);
+        Ok(Address(payload))
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(seed: u8) -> Address {
+        Address::from_secret_key(&SecretKey::from_slice(&[seed; 32]).unwrap())
+    }
+
+    #[test]
+    fn test_address_round_trip() {
+        let address = address(1);
+        let encoded = address.to_string();
+        assert_eq!(encoded.parse::<Address>().unwrap(), address);
+        assert_eq!(
+            serde_json::from_str::<Address>(&serde_json::to_string(&address).unwrap()).unwrap(),
+            address
+        );
+    }
+
+    #[test]
+    fn test_address_typo_is_rejected() {
+        let encoded = address(1).to_string();
+        // Swap one character for a different valid Base58 character
+        let mut chars: Vec<char> = encoded.chars().collect();
+        let last = chars.len() - 1;
+        chars[last] = if chars[last] == '2' { '3' } else { '2' };
+        let typo: String = chars.into_iter().collect();
+        assert!(typo.parse::<Address>().is_err());
+        assert!("not an address".parse::<Address>().is_err());
+        assert!(serde_json::from_str::<Address>(&format!("\"{}\"", typo)).is_err());
+    }
+}
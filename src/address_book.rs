@@ -0,0 +1,300 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::custom_error::CustomError;
+
+// Most addresses we remember, so peers can't make the book grow without bounds
+pub const MAX_ADDRESSES: usize = 1000;
+// Most addresses sent in, or accepted from, a single `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+// Seconds after which an address nobody mentioned anymore is forgotten
+const ADDRESS_HORIZON: i64 = 30 * 24 * 60 * 60;
+// Seconds to wait before retrying an address, multiplied by the number of failures in a row
+const RETRY_DELAY: i64 = 60;
+// Failures in a row after which an address that never worked is forgotten
+const MAX_FAILURES: u32 = 10;
+
+// What we know about a node we could connect to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAddress {
+    pub address: String,
+    pub last_seen: i64, // When the node was last known to be up, by us or a peer
+    pub last_attempt: i64, // When we last tried to connect, 0 if never
+    pub last_success: i64, // When we last completed a handshake with it, 0 if never
+    pub successes: u32,
+    pub failures: u32, // Failed attempts since the last success
+}
+
+impl PeerAddress {
+    // Whether the address failed recently enough that we should leave it alone for now
+    fn is_backing_off(&self, now: i64) -> bool {
+        self.failures > 0 && now - self.last_attempt < RETRY_DELAY * self.failures as i64
+    }
+}
+
+// Network group of an address: peers in the same group are likely run by the same operator,
+// so selecting from as many groups as possible makes it harder for one party to surround us.
+fn network_group(address: &str) -> String {
+    match address.parse::<SocketAddr>().map(|address| address.ip()) {
+        Ok(IpAddr::V4(ip)) => {
+            let octets = ip.octets();
+            format!("{}.{}", octets[0], octets[1])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+        Err(_) => address.to_string(),
+    }
+}
+
+/// Whether an address means the same node to every peer. Loopback and unspecified addresses only make sense
+/// on the machine they come from, so they are never passed on or taken from peers.
+pub fn is_shareable(address: &str) -> bool {
+    address
+        .parse::<SocketAddr>()
+        .is_ok_and(|address| !address.ip().is_loopback() && !address.ip().is_unspecified())
+}
+
+// Every address we have heard of, with how well connecting to it went. It is kept on disk so a restarted node
+// doesn't depend on its seed nodes to find the network again.
+#[derive(Clone, Debug, Default)]
+pub struct AddressBook {
+    addresses: HashMap<String, PeerAddress>,
+    path: Option<PathBuf>,
+}
+
+impl AddressBook {
+    /// Loads the book saved at `path`, or starts an empty one there if there is none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CustomError> {
+        let path = path.as_ref().to_path_buf();
+        let addresses: Vec<PeerAddress> = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        Ok(AddressBook {
+            addresses: addresses
+                .into_iter()
+                .map(|entry| (entry.address.clone(), entry))
+                .collect(),
+            path: Some(path),
+        })
+    }
+
+    /// Writes the book to the file it was loaded from. A book that wasn't loaded from a file isn't saved.
+    pub fn save(&self) -> Result<(), CustomError> {
+        if let Some(path) = &self.path {
+            let addresses: Vec<&PeerAddress> = self.addresses.values().collect();
+            fs::write(path, serde_json::to_string_pretty(&addresses)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, address: &str) -> Option<&PeerAddress> {
+        self.addresses.get(address)
+    }
+
+    /// Records that the node at `address` was up at time `seen`. Anything but an IP address with a port is ignored.
+    /// When the book is full, the least useful address makes room.
+    pub fn add(&mut self, address: &str, seen: i64) {
+        if address.parse::<SocketAddr>().is_err() {
+            return;
+        }
+        if let Some(entry) = self.addresses.get_mut(address) {
+            entry.last_seen = entry.last_seen.max(seen);
+            return;
+        }
+        if self.addresses.len() >= MAX_ADDRESSES {
+            let worst = self
+                .addresses
+                .values()
+                .min_by_key(|entry| {
+                    (
+                        entry.successes > 0,
+                        -(entry.failures as i64),
+                        entry.last_seen,
+                    )
+                })
+                .map(|entry| entry.address.clone());
+            if let Some(worst) = worst {
+                self.addresses.remove(&worst);
+            }
+        }
+        self.addresses.insert(
+            address.to_string(),
+            PeerAddress {
+                address: address.to_string(),
+                last_seen: seen,
+                last_attempt: 0,
+                last_success: 0,
+                successes: 0,
+                failures: 0,
+            },
+        );
+    }
+
+    pub fn mark_success(&mut self, address: &str, now: i64) {
+        self.add(address, now);
+        if let Some(entry) = self.addresses.get_mut(address) {
+            entry.last_attempt = now;
+            entry.last_success = now;
+            entry.successes += 1;
+            entry.failures = 0;
+        }
+    }
+
+    /// Records a failed connection attempt. Addresses that never worked are forgotten after `MAX_FAILURES` in a row.
+    pub fn mark_failure(&mut self, address: &str, now: i64) {
+        if let Some(entry) = self.addresses.get_mut(address) {
+            entry.last_attempt = now;
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES && entry.successes == 0 {
+                self.addresses.remove(address);
+            }
+        }
+    }
+
+    /// Forgets addresses nobody has seen within the horizon.
+    pub fn expire(&mut self, now: i64) {
+        self.addresses
+            .retain(|_, entry| now - entry.last_seen <= ADDRESS_HORIZON);
+    }
+
+    /// The most recently seen addresses, to answer a `GetAddr` with.
+    pub fn addresses_to_share(&self, now: i64) -> Vec<(String, i64)> {
+        let mut shared: Vec<&PeerAddress> = self
+            .addresses
+            .values()
+            .filter(|entry| now - entry.last_seen <= ADDRESS_HORIZON && entry.failures == 0)
+            .filter(|entry| is_shareable(&entry.address))
+            .collect();
+        shared.sort_by_key(|entry| -entry.last_seen);
+        shared
+            .into_iter()
+            .take(MAX_ADDR_PER_MESSAGE)
+            .map(|entry| (entry.address.clone(), entry.last_seen))
+            .collect()
+    }
+
+    /// Picks up to `count` addresses to connect to, skipping `exclude` and addresses that failed too recently.
+    /// Addresses that worked recently come first, and they are taken from as many network groups as possible.
+    pub fn select(&self, count: usize, now: i64, exclude: &[String]) -> Vec<String> {
+        let mut groups: BTreeMap<String, Vec<&PeerAddress>> = BTreeMap::new();
+        for entry in self.addresses.values() {
+            if !exclude.contains(&entry.address) && !entry.is_backing_off(now) {
+                groups
+                    .entry(network_group(&entry.address))
+                    .or_default()
+                    .push(entry);
+            }
+        }
+        for entries in groups.values_mut() {
+            // Best last, so `pop` takes the best remaining address of the group
+            entries
+                .sort_by_key(|entry| (entry.last_success, entry.last_seen, entry.address.clone()));
+        }
+
+        // One address from every group in turn, until we have enough or run out
+        let mut selected = vec![];
+        while selected.len() < count && groups.values().any(|entries| !entries.is_empty()) {
+            for entries in groups.values_mut() {
+                if selected.len() < count {
+                    if let Some(entry) = entries.pop() {
+                        selected.push(entry.address.clone());
+                    }
+                }
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_prefers_good_and_diverse_addresses() {
+        let mut book = AddressBook::default();
+        book.add("not an address", 100);
+        assert!(book.addresses.is_empty());
+
+        for address in [
+            "10.0.0.1:8000",
+            "10.0.0.2:8000",
+            "10.0.0.3:8000",
+            "10.1.0.1:8000",
+        ] {
+            book.add(address, 100);
+        }
+        book.mark_success("10.0.0.2:8000", 200);
+
+        // The best address of each group first, then the rest
+        assert_eq!(
+            book.select(2, 300, &[]),
+            vec!["10.0.0.2:8000".to_string(), "10.1.0.1:8000".to_string()]
+        );
+        assert_eq!(book.select(10, 300, &[]).len(), 4);
+        let exclude = vec!["10.0.0.2:8000".to_string()];
+        assert!(!book.select(10, 300, &exclude).contains(&exclude[0]));
+
+        // A failed address is left alone for a while, longer with every failure
+        book.mark_failure("10.1.0.1:8000", 300);
+        assert!(!book
+            .select(10, 300 + RETRY_DELAY - 1, &[])
+            .contains(&"10.1.0.1:8000".to_string()));
+        assert!(book
+            .select(10, 300 + RETRY_DELAY, &[])
+            .contains(&"10.1.0.1:8000".to_string()));
+        for _ in 1..MAX_FAILURES {
+            book.mark_failure("10.1.0.1:8000", 300);
+        }
+        assert!(book.get("10.1.0.1:8000").is_none());
+
+        // Failing and local addresses aren't passed on, and old ones are forgotten
+        book.mark_failure("10.0.0.3:8000", 300);
+        book.add("127.0.0.1:8000", 100);
+        let shared: Vec<String> = book
+            .addresses_to_share(300)
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        assert_eq!(shared[0], "10.0.0.2:8000");
+        assert!(!shared.contains(&"10.0.0.3:8000".to_string()));
+        assert!(!shared.contains(&"127.0.0.1:8000".to_string()));
+        book.expire(200 + ADDRESS_HORIZON);
+        assert_eq!(book.addresses.len(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("address_book_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut book = AddressBook::load(&path).unwrap();
+        assert!(book.addresses.is_empty());
+        book.mark_success("127.0.0.1:8000", 100);
+        book.add("127.0.0.1:8001", 50);
+        book.save().unwrap();
+
+        let loaded = AddressBook::load(&path).unwrap();
+        assert_eq!(loaded.addresses.len(), 2);
+        assert_eq!(loaded.get("127.0.0.1:8000"), book.get("127.0.0.1:8000"));
+        fs::remove_file(&path).unwrap();
+
+        // The book stays bounded
+        let mut book = AddressBook::default();
+        for port in 0..MAX_ADDRESSES as u16 + 10 {
+            book.add(&format!("127.0.0.1:{}", port), 0);
+        }
+        assert_eq!(book.addresses.len(), MAX_ADDRESSES);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::custom_error::CustomError;
+
+// Number of decimal places a coin can be divided into
+pub const DECIMALS: usize = 8;
+// Number of base units in one whole coin
+pub const UNITS_PER_COIN: u64 = 100_000_000;
+// No amount, balance or cumulative issuance may ever exceed this
+pub const MAX_SUPPLY: Amount = Amount(21_000_000 * UNITS_PER_COIN);
+
+// A quantity of coins, stored as an integer number of base units so every node computes identical balances.
+// Serialized as the raw unit count.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub fn from_coins(coins: u64) -> Option<Self> {
+        coins.checked_mul(UNITS_PER_COIN).map(Amount)
+    }
+
+    pub const fn units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether this amount lies within the range any single value is allowed to take.
+    pub fn is_valid(&self) -> bool {
+        *self <= MAX_SUPPLY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Sums a sequence of amounts, returning `None` on overflow.
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+// Renders as a decimal coin value without trailing zeros, e.g. "1.5" or "21"
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whole = self.0 / UNITS_PER_COIN;
+        let fraction = self.0 % UNITS_PER_COIN;
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+        let fraction = format!("{:0width$}", fraction, width = DECIMALS);
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Amount({})", self)
+    }
+}
+
+// Parses a decimal coin value such as "12", "0.5" or "3.00000001"
+impl FromStr for Amount {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, fraction) = match s.split_once('.') {
+            Some((_, "")) => return Err(CustomError::new("Amount is not a decimal number")),
+            Some((whole, fraction)) => (whole, fraction),
+            None => (s, ""),
+        };
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(CustomError::new("Amount is not a decimal number"));
+        }
+        if fraction.len() > DECIMALS {
+            return Err(CustomError::new("Amount has too many decimal places"));
+        }
+
+        let overflow = || CustomError::new("Amount overflows");
+        let whole: u64 = whole.parse().map_err(|_| overflow())?;
+        let fraction: u64 = if fraction.is_empty() {
+            0
+        } else {
+            format!("{:0<width$}", fraction, width = DECIMALS)
+                .parse()
+                .map_err(|_| overflow())?
+        };
+        Amount::from_coins(whole)
+            .and_then(|amount| amount.checked_add(Amount(fraction)))
+            .ok_or_else(overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_display_and_parse() {
+        for (text, units) in [
+            ("0", 0),
+            ("1", UNITS_PER_COIN),
+            ("1.5", 150_000_000),
+            ("0.00000001", 1),
+            ("21000000", MAX_SUPPLY.units()),
+        ] {
+            let amount: Amount = text.parse().unwrap();
+            assert_eq!(amount.units(), units);
+            assert_eq!(amount.to_string(), text);
+        }
+        assert_eq!("2.50".parse::<Amount>().unwrap().to_string(), "2.5");
+
+        for bad in ["", ".5", "1.", "-1", "1.000000001", "1e5", "184467440738"] {
+            assert!(bad.parse::<Amount>().is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let one = Amount::from_units(1);
+        assert_eq!(Amount::from_units(u64::MAX).checked_add(one), None);
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(
+            Amount::checked_sum([one, one, one]),
+            Some(Amount::from_units(3))
+        );
+        assert!(MAX_SUPPLY.is_valid());
+        assert!(!MAX_SUPPLY.checked_add(one).unwrap().is_valid());
+    }
+}
@@ -1,18 +1,77 @@
+use primitive_types::U256;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::transaction::Transaction;
+use crate::{
+    difficulty,
+    encoding::{encode, Encode, Encoder},
+    merkle::{self, MerkleProof},
+    params::INITIAL_BITS,
+    transaction::Transaction,
+};
 
-const DIFFICULTY: usize = 4; // Adjust to your desired difficulty
+// Limits on what a single block may hold
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1_000;
+pub const MAX_BLOCK_SIZE: usize = 1_000_000; // Encoded transaction bytes
 
+// Version of the block header layout
+pub const BLOCK_VERSION: u32 = 1;
 
-// Represents individual blocks in the blockchain
+// Everything the proof of work commits to. The transactions are only committed to through the Merkle root,
+// so headers can be synced and validated without the block bodies.
 #[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Block {
+pub struct BlockHeader {
+    pub version: u32,
     pub index: u32,
     pub timestamp: i64,
-    pub nonce: u32,
     pub previous_hash: String,
+    pub merkle_root: String,
+    pub bits: u32, // Compact encoding of the target the block hash must not exceed
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(encode(self));
+        let result = hasher.finalize();
+        hex::encode(result) // Using the hex crate to convert the hash to a hexadecimal string
+    }
+
+    /// Checks that `proof` places a transaction in this block, using nothing but the header.
+    /// The header must carry valid proof of work, but that is cheap at an easy target of the header's own choosing,
+    /// so only a header known to be on the chain, see `Blockchain::verify_transaction_proof`, makes the proof count.
+    pub fn verify_merkle_proof(&self, proof: &MerkleProof) -> bool {
+        self.meets_target(&self.hash()) && proof.verify(&self.merkle_root)
+    }
+
+    /// Expected number of hashes it took to mine a block with this header's target.
+    pub fn work(&self) -> U256 {
+        difficulty::block_work(self.bits)
+    }
+
+    /// Checks `hash`, read as a 256-bit number, against the target of this header.
+    pub fn meets_target(&self, hash: &str) -> bool {
+        difficulty::hash_meets_target(hash, self.bits)
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.u32(self.version);
+        encoder.u32(self.index);
+        encoder.i64(self.timestamp);
+        encoder.str(&self.previous_hash);
+        encoder.str(&self.merkle_root);
+        encoder.u32(self.bits);
+        encoder.u32(self.nonce);
+    }
+}
+
+// Represents individual blocks in the blockchain: the header plus the body of transactions
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Block {
+    pub header: BlockHeader,
     pub hash: String,
     pub transactions: Vec<Transaction>, // Assume Transaction is defined
 }
@@ -26,55 +85,151 @@ impl Block {
         previous_hash: String,
         transactions: Vec<Transaction>,
     ) -> Self {
-        let mut block = Block {
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
             index,
             timestamp,
-            nonce,
             previous_hash,
-            hash: String::new(),
-            transactions,
+            merkle_root: merkle::merkle_root(&txids(&transactions)),
+            // Until it's mined for a particular network, a block has the mainnet's initial target
+            bits: INITIAL_BITS,
+            nonce,
         };
-        block.hash = block.calculate_hash();
-        block
+        Block {
+            hash: header.hash(),
+            header,
+            transactions,
+        }
     }
 
     // Proof of work algorithm
     // &mut self -> mutable reference of self Block
-    // &self.hash[0..] - reference slice - checking leading zeros
+    // Only the header is hashed, so every attempt is cheap no matter how many transactions the block holds
     // icrement nonce and calculate the hash again
     pub fn mine_block(&mut self) {
-        while &self.hash[0..DIFFICULTY] != "0".repeat(DIFFICULTY).as_str() {
-            self.nonce += 1;
+        // The header may have changed since the hash was last calculated, e.g. to set the target
+        self.hash = self.calculate_hash();
+        while !self.header.meets_target(&self.hash) {
+            self.header.nonce += 1;
             self.hash = self.calculate_hash();
         }
         println!("Block mined: {}", self.hash);
     }
 
     pub fn calculate_hash(&self) -> String {
-        let data = format!(
-            "{}{}{}{}{:?}",
-            self.index, self.timestamp, self.previous_hash, self.nonce, self.transactions
-        );
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result) // Using the hex crate to convert the hash to a hexadecimal string
+        self.header.hash()
+    }
+
+    /// Merkle root of the transactions in the body, which a valid block has in its header.
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle::merkle_root(&txids(&self.transactions))
+    }
+
+    /// Whether the body lists a transaction twice, which the Merkle root alone doesn't reveal.
+    pub fn has_duplicate_transactions(&self) -> bool {
+        merkle::has_duplicates(&txids(&self.transactions))
+    }
+
+    /// Merkle branch proving that the transaction with id `txid` is in this block.
+    pub fn merkle_proof(&self, txid: &str) -> Option<MerkleProof> {
+        let txids = txids(&self.transactions);
+        let index = txids.iter().position(|id| id == txid)?;
+        merkle::merkle_proof(&txids, index)
     }
 
     // Other methods like mining can be added here
 }
 
+fn txids(transactions: &[Transaction]) -> Vec<String> {
+    transactions.iter().map(Transaction::hash).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{address::Address, amount::Amount, params::ChainParams};
+    use secp256k1::SecretKey;
 
     #[test]
     fn test_block_hash_changes_with_nonce() {
         let mut block = Block::new(0, 0, 0, String::from("0"), Vec::new());
         let original_hash = block.hash.clone();
-        block.nonce = 1;
+        block.header.nonce = 1;
         assert_ne!(original_hash, block.calculate_hash());
     }
 
+    #[test]
+    fn test_header_commits_to_transactions() {
+        let bob = Address::from_secret_key(&SecretKey::from_slice(&[2; 32]).unwrap());
+        let coinbase = Transaction::coinbase(bob, Amount::from_units(1), 1);
+        let mut block = Block::new(1, 0, 0, String::from("0"), vec![coinbase]);
+        assert_eq!(block.header.merkle_root, block.calculate_merkle_root());
+
+        // Changing the body leaves the hash alone but breaks the Merkle root
+        block.transactions[0].amount = Amount::from_units(2);
+        assert_eq!(block.hash, block.calculate_hash());
+        assert_ne!(block.header.merkle_root, block.calculate_merkle_root());
+    }
+
+    #[test]
+    fn test_merkle_proof_against_header() {
+        let bob = Address::from_secret_key(&SecretKey::from_slice(&[2; 32]).unwrap());
+        let transactions: Vec<Transaction> = (1..=3)
+            .map(|height| Transaction::coinbase(bob, Amount::from_units(1), height))
+            .collect();
+        let mut block = Block::new(1, 0, 0, String::from("0"), transactions);
+        block.header.bits = ChainParams::mainnet().pow_limit_bits;
+        block.mine_block();
+
+        let txid = block.transactions[2].hash();
+        let proof = block.merkle_proof(&txid).unwrap();
+        let header = block.header.clone();
+        assert!(header.verify_merkle_proof(&proof));
+        assert!(block.merkle_proof(&"00".repeat(32)).is_none());
+
+        // A header without proof of work doesn't prove anything
+        let mut unmined = header;
+        unmined.bits = 0x03000001;
+        assert!(!unmined.verify_merkle_proof(&proof));
+    }
+
+    // Pinned hashes of known blocks. If these change, the encoding changed and `ENCODING_VERSION` must be bumped.
+    #[test]
+    fn test_block_hash_vectors() {
+        let genesis = Block::new(0, 0, 0, String::from("0"), Vec::new());
+        assert_eq!(
+            genesis.hash,
+            "3696fb4b2eedbe01f7e53ff8bfb02d1af25f2a124ceacc3bbb3489776f6de52f"
+        );
+
+        let alice = SecretKey::from_slice(&[1; 32]).unwrap();
+        let bob = Address::from_secret_key(&SecretKey::from_slice(&[2; 32]).unwrap());
+        let mut payment = Transaction::new(
+            Address::from_secret_key(&alice),
+            bob,
+            Amount::from_units(150_000_000),
+            Amount::from_units(1_000),
+            0,
+        );
+        payment.sign(&alice);
+        assert_eq!(
+            payment.hash(),
+            "911b01906aeeaec9b699a3198ee0cd55640b08990f0b41ec0f1f1c85f3992068"
+        );
+
+        let coinbase = Transaction::coinbase(bob, Amount::from_units(5_000_001_000), 1);
+        let block = Block::new(
+            1,
+            1_700_000_000,
+            42,
+            genesis.hash.clone(),
+            vec![coinbase, payment],
+        );
+        assert_eq!(
+            block.hash,
+            "28bb259ce853e4fac50194d7429168cfebd71af8fde690c5a088919664a46051"
+        );
+    }
+
     // Add more tests for the block...
 }
@@ -1,161 +1,1878 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
 use chrono::Utc;
+use primitive_types::U256;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    address::Address,
+    amount::{Amount, MAX_SUPPLY},
+    block::{Block, BlockHeader, MAX_BLOCK_SIZE, MAX_BLOCK_TRANSACTIONS},
+    custom_error::CustomError,
+    difficulty,
+    merkle::MerkleProof,
+    orphans::OrphanPool,
+    params::ChainParams,
+    reward,
+    timestamps::{self, NetworkTime, MEDIAN_TIME_SPAN},
+    transaction::Transaction,
+    utxo::{LedgerMode, OutPoint, TxOutput, UtxoSet},
+    validation::{ValidationError, ValidationRule},
+};
+
+// Side branches whose tip falls more than this many blocks behind the active chain are pruned, and no longer accepted
+const MAX_FORK_DEPTH: usize = 100;
+// Number of chain events buffered for a subscriber that falls behind
+const EVENT_CAPACITY: usize = 64;
 
-use crate::{block::Block, transaction::Transaction};
+// Changes to the active chain, sent to everyone who subscribed with `Blockchain::subscribe`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainEvent {
+    // A block was appended to the active chain, either on its own or as part of a reorganization
+    BlockConnected {
+        hash: String,
+        index: u32,
+    },
+    // The active chain switched to a branch with more work. Blocks are listed from the fork point towards the tips.
+    Reorganized {
+        fork_point: String,
+        disconnected: Vec<String>,
+        connected: Vec<String>,
+    },
+}
+
+// A block in the block tree, together with the total work of the branch ending in it
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BlockEntry {
+    pub block: Block,
+    pub cumulative_work: U256,
+}
 
 // Manages the entire chain of blocks, adding new blocks, validating the chain, handling transactions, etc...
+// Every known block is kept in a tree indexed by hash. `chain` is the branch with the most work, the active chain,
+// and the ledger state always reflects it.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Blockchain {
     pub chain: Vec<Block>,
-    pub peers: Vec<String>,  // Add this
     pub pending_transactions: Vec<Transaction>,
-    difficulty: usize,
+    params: ChainParams,
+    ledger_mode: LedgerMode,
+    nonces: HashMap<Address, u64>, // Next expected nonce of every account that has sent a confirmed transaction
+    utxos: UtxoSet,                // Unspent outputs of the chain, only maintained in UTXO mode
+    spent: HashMap<String, Vec<(OutPoint, TxOutput)>>, // Outputs each block of the active chain spent, to undo it
+    issued: Amount, // Coins minted by the coinbase transactions of the chain so far
+    blocks: HashMap<String, BlockEntry>, // Every known block, on the active chain or a side branch
+    tips: HashSet<String>, // Hashes of the blocks nothing builds on yet
+    children: HashMap<String, Vec<String>>, // Hashes of the blocks building on each block that has any
+    #[serde(skip)]
+    orphans: OrphanPool,  // Blocks whose parent hasn't arrived yet
+    #[serde(skip)]
+    network_time: NetworkTime, // Offsets of our peers' clocks from ours
+    #[serde(skip, default = "new_events")]
+    events: broadcast::Sender<ChainEvent>,
 }
 
 impl Blockchain {
-    // Creates a blockchain with a genesis block
-    pub fn new() -> Self {
-        let mut chain = Vec::new();
-        let genesis_block = Blockchain::create_genesis_block();
-        chain.push(genesis_block);
+    // Creates a blockchain of the network described by `params`, starting from its genesis block, that tracks
+    // balances according to `ledger_mode`
+    pub fn new(params: ChainParams, ledger_mode: LedgerMode) -> Self {
+        let genesis = params.genesis_block();
+        let mut blockchain = Blockchain::empty(params, ledger_mode);
+        blockchain.connect_block(genesis);
+        blockchain
+    }
 
+    // A blockchain without even a genesis block
+    fn empty(params: ChainParams, ledger_mode: LedgerMode) -> Self {
         Blockchain {
-            chain,
-            peers: vec![],
+            chain: vec![],
             pending_transactions: vec![],
-            difficulty: 4,
+            params,
+            ledger_mode,
+            nonces: HashMap::new(),
+            utxos: UtxoSet::new(),
+            spent: HashMap::new(),
+            issued: Amount::ZERO,
+            blocks: HashMap::new(),
+            tips: HashSet::new(),
+            children: HashMap::new(),
+            orphans: OrphanPool::new(),
+            network_time: NetworkTime::new(),
+            events: new_events(),
         }
     }
 
-    fn create_genesis_block() -> Block {
-        // Define the genesis block with index 0 and a hardcoded previous hash
-        Block::new(0, 0, 0, String::from("0"), Vec::new())
+    /// Records the time a peer reported, which goes into the network-adjusted time.
+    pub fn add_time_sample(&mut self, peer: &str, peer_time: i64) {
+        self.network_time
+            .add_sample(peer, peer_time, Utc::now().timestamp());
+    }
+
+    /// Stops counting the time a peer reported, once we are no longer connected to it.
+    pub fn remove_time_sample(&mut self, peer: &str) {
+        self.network_time.remove_sample(peer);
+    }
+
+    /// Our clock, corrected by the median offset of our peers' clocks.
+    pub fn adjusted_time(&self) -> i64 {
+        self.network_time.adjusted_time(Utc::now().timestamp())
+    }
+
+    // Timestamp for a block on top of the active chain: the current time, unless that isn't past the median yet
+    fn next_timestamp(&self) -> i64 {
+        self.adjusted_time()
+            .max(timestamps::median_time_past(&self.chain) + 1)
     }
 
-    /// Validates a list of transactions.
+    /// Receives an event for every change to the active chain from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Looks up a known block by hash, whether it is on the active chain or a side branch.
+    pub fn block(&self, hash: &str) -> Option<&BlockEntry> {
+        self.blocks.get(hash)
+    }
+
+    /// The parameters of the network this blockchain belongs to.
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Validates the transactions of a candidate block, in order.
+    /// Each transaction is checked against the confirmed state plus the earlier transactions of the same block,
+    /// both for the balance it spends and for its nonce.
     pub fn validate_transactions(&self, transactions: &[Transaction]) -> bool {
+        let mut committed = Commitments::default();
         for tx in transactions {
-            if !self.validate_transaction(tx) {
+            if !self.admits(tx, &mut committed) {
                 return false;
             }
+            committed.commit(tx);
         }
         true
     }
 
-    /// Validates a single transaction (e.g., by checking its signature).
-    /// This is a stub and would need a lot more logic based on your blockchain's rules.
+    /// Validates a single transaction for the mempool: its signature, that the sender address belongs to the signing key,
+    /// that the sender can afford it on top of everything they already have pending, and that its nonce comes right after them.
     pub fn validate_transaction(&self, transaction: &Transaction) -> bool {
-        // Assume the Transaction struct has a verify method that checks its signature.
-        // This is just an example, your actual verification might look different.
-        transaction.verify()
+        let mut committed = Commitments::default();
+        for tx in &self.pending_transactions {
+            committed.commit(tx);
+        }
+        self.admits(transaction, &mut committed)
+    }
+
+    // Validates a transaction as if the `committed` transactions were applied right before it
+    fn admits(&self, transaction: &Transaction, committed: &mut Commitments) -> bool {
+        if !transaction.verify() {
+            return false;
+        }
+        match self.ledger_mode {
+            LedgerMode::Account => self.validate_account_transaction(transaction, committed),
+            LedgerMode::Utxo => self.validate_utxo_transaction(transaction, committed),
+        }
+    }
+
+    fn validate_account_transaction(
+        &self,
+        transaction: &Transaction,
+        committed: &mut Commitments,
+    ) -> bool {
+        // Account transactions move balances directly and never reference outputs
+        if !transaction.inputs.is_empty() || !transaction.change.is_zero() {
+            return false;
+        }
+
+        // Nonces have to be used in sequence, which rules out replays and out-of-order transactions
+        if transaction.nonce != self.committed_nonce(&transaction.sender, committed) {
+            return false;
+        }
+
+        // Confirmed balance minus everything the sender already committed must cover the amount and fee
+        let balance = *committed
+            .balances
+            .entry(transaction.sender)
+            .or_insert_with(|| self.get_balance(&transaction.sender).ok());
+        let already_spent = committed
+            .senders
+            .get(&transaction.sender)
+            .map_or(Some(Amount::ZERO), |(_, spent)| *spent);
+        match (balance, already_spent, transaction.cost()) {
+            (Some(balance), Some(already_spent), Some(cost)) => balance
+                .checked_sub(already_spent)
+                .and_then(|available| available.checked_sub(cost))
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    // Spent outputs can't be spent again, so UTXO transactions need no nonce for replay protection
+    fn validate_utxo_transaction(
+        &self,
+        transaction: &Transaction,
+        committed: &Commitments,
+    ) -> bool {
+        if transaction.inputs.is_empty() {
+            return false;
+        }
+
+        let mut input_total = Amount::ZERO;
+        for (i, input) in transaction.inputs.iter().enumerate() {
+            // Every input must be an unspent output owned by the sender
+            let output = match self.utxos.get(input) {
+                Some(output) if output.address == transaction.sender => output,
+                _ => return false,
+            };
+            // that nothing else spends first
+            if transaction.inputs[..i].contains(input) || committed.inputs.contains(input) {
+                return false;
+            }
+            input_total = match input_total.checked_add(output.amount) {
+                Some(total) => total,
+                None => return false,
+            };
+        }
+
+        // The outputs can't create more than the inputs bring in, and whatever is left over is the fee
+        let output_total =
+            Amount::checked_sum(transaction.outputs().iter().map(|output| output.amount));
+        output_total.and_then(|total| total.checked_add(transaction.fee)) == Some(input_total)
     }
 
-    /// Check if a block's hash meets the difficulty requirement for mining.
-    pub fn is_valid_proof(&self, block: &Block) -> bool {
-        let num_zeros = self.get_difficulty();  // Example: return 4 for "0000"
-        let prefix = "0".repeat(num_zeros);
-        block.hash.starts_with(&prefix)
+    // The nonce the next transaction of `address` has to carry after the `committed` ones
+    fn committed_nonce(&self, address: &Address, committed: &Commitments) -> u64 {
+        let count = committed
+            .senders
+            .get(address)
+            .map_or(0, |(count, _)| *count);
+        self.next_nonce(address) + count
     }
 
-    /// Get the current mining difficulty. This could be a static value or dynamic based on blockchain length or other factors.
-    pub fn get_difficulty(&self) -> usize {
-        // Just a static example, you might adjust this based on your blockchain's needs.
-        4
+    /// The nonce the next confirmed transaction of `address` has to carry.
+    pub fn next_nonce(&self, address: &Address) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
     }
-    pub fn add_transaction(&mut self, transaction: Transaction) {
-        // TODO: Add validation
+
+    // Advances the expected nonces past the transactions of a block appended to the chain
+    fn apply_nonces(&mut self, block: &Block) {
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            self.nonces.insert(tx.sender, tx.nonce + 1);
+        }
+    }
+
+    // Checks that `block` commits to the target expected on top of `chain` and that its hash meets it
+    fn has_valid_proof(&self, chain: &[Block], block: &Block) -> bool {
+        block.header.bits == difficulty::next_difficulty(&self.params, chain)
+            && block.header.meets_target(&block.hash)
+    }
+
+    /// Get the compact target the next block has to meet, retargeted from the timestamps of the chain.
+    pub fn get_difficulty(&self) -> u32 {
+        difficulty::next_difficulty(&self.params, &self.chain)
+    }
+
+    /// Adds a transaction to the pool of pending transactions if it is valid and affordable.
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), &'static str> {
+        if !self.validate_transaction(&transaction) {
+            return Err("Invalid transaction");
+        }
         self.pending_transactions.push(transaction);
+        Ok(())
+    }
+
+    /// Picks the pending transactions for the next block, best fee rate first, within the block limits.
+    /// A transaction is only picked once it is valid on top of those picked before it, so nonces stay in order.
+    pub fn block_template(&self) -> Vec<Transaction> {
+        let mut candidates: Vec<(&Transaction, usize)> = self
+            .pending_transactions
+            .iter()
+            .map(|tx| (tx, tx.size()))
+            .collect();
+        // Highest fee per byte first, compared by cross-multiplying to stay exact
+        candidates.sort_by(|(a, a_size), (b, b_size)| {
+            let a_rate = a.fee.units() as u128 * *b_size as u128;
+            let b_rate = b.fee.units() as u128 * *a_size as u128;
+            b_rate.cmp(&a_rate)
+        });
+
+        // Candidates are taken best first. One whose nonce isn't due yet waits until its predecessor is picked,
+        // anything else that doesn't fit or isn't valid now never will be, as the block only grows.
+        let mut ready: BinaryHeap<Reverse<usize>> = (0..candidates.len()).map(Reverse).collect();
+        let mut waiting: HashMap<(Address, u64), Vec<usize>> = HashMap::new();
+        let mut committed = Commitments::default();
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut block_size = coinbase_size();
+        while selected.len() < MAX_BLOCK_TRANSACTIONS {
+            let Some(Reverse(i)) = ready.pop() else {
+                break;
+            };
+            let (tx, size) = candidates[i];
+            if block_size + size > MAX_BLOCK_SIZE {
+                continue;
+            }
+            if self.ledger_mode == LedgerMode::Account
+                && tx.nonce > self.committed_nonce(&tx.sender, &committed)
+            {
+                waiting.entry((tx.sender, tx.nonce)).or_default().push(i);
+                continue;
+            }
+            if !self.admits(tx, &mut committed) {
+                continue;
+            }
+
+            committed.commit(tx);
+            block_size += size;
+            selected.push(tx.clone());
+            if let Some(next) = waiting.remove(&(tx.sender, tx.nonce + 1)) {
+                ready.extend(next.into_iter().map(Reverse));
+            }
+        }
+        selected
     }
 
-    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<(), &'static str> {
-        // Validate transactions (assuming you've a function for that)
+    /// Assembles the next block from the best pending transactions, paying the subsidy and their fees to `miner`.
+    /// The block still has to be mined, which can be done without holding on to the blockchain, and then accepted.
+    pub fn pending_block(&self, miner: &Address) -> Result<Block, &'static str> {
+        self.new_block(self.block_template(), miner)
+    }
+
+    // Builds the unmined block on top of the active chain that holds `transactions` after a coinbase paying `miner`
+    fn new_block(
+        &self,
+        transactions: Vec<Transaction>,
+        miner: &Address,
+    ) -> Result<Block, &'static str> {
+        let size: usize = transactions.iter().map(Transaction::size).sum();
+        if transactions.len() > MAX_BLOCK_TRANSACTIONS || coinbase_size() + size > MAX_BLOCK_SIZE {
+            return Err(ValidationRule::Size.message());
+        }
+
+        // Validate transactions before spending the work on mining them
         if !self.validate_transactions(&transactions) {
-            return Err("Invalid transactions");
+            return Err(ValidationRule::Transactions.message());
         }
-    
+
         let previous_block = self.chain.last().unwrap();
-        let index = previous_block.index + 1;
-        let timestamp = Utc::now().timestamp();
+        let index = previous_block.header.index + 1;
+        let timestamp = self.next_timestamp();
         let nonce = 0;
         let previous_hash = previous_block.hash.clone();
-        let mut block = Block::new(index, timestamp, nonce, previous_hash, transactions);
-    
-        block.mine_block();
-    
-        // Check if mined block's hash meets the difficulty requirement
-        if !self.is_valid_proof(&block) {
-            return Err("Block did not meet difficulty requirement");
+        let fees = total_fees(&transactions).ok_or("Transaction fees overflow")?;
+        let reward = reward::allowed_subsidy(&self.params, index, self.issued)
+            .checked_add(fees)
+            .ok_or("Block reward overflows")?;
+        let mut block_transactions = vec![Transaction::coinbase(*miner, reward, index)];
+        block_transactions.extend(transactions);
+        let mut block = Block::new(index, timestamp, nonce, previous_hash, block_transactions);
+        block.header.bits = self.get_difficulty();
+        Ok(block)
+    }
+
+    /// Adds a block received from a peer to the block tree exactly as it was mined. Its header, proof of work and
+    /// timestamp are checked against its parent, and a block extending the active chain is connected right away if
+    /// its transactions are valid. A block on a side branch is kept until its branch has more work than the active
+    /// chain, which then reorganizes onto it. Orphans waiting for the block are placed right after it.
+    /// Fails with the block and the rule it breaks, which is the first block of the branch that doesn't connect
+    /// when a reorganization fails.
+    pub fn accept_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        let hash = block.hash.clone();
+        self.place_block(block)?;
+
+        self.orphans.expire(Utc::now().timestamp());
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.take_children(&parent) {
+                let hash = orphan.hash.clone();
+                if self.place_block(orphan).is_ok() {
+                    parents.push(hash);
+                }
+            }
         }
-    
-        self.chain.push(block);
         Ok(())
     }
-    
 
+    /// Holds on to a block from `peer` whose parent we don't have yet, until the parent arrives through `accept_block`.
+    /// Only blocks carrying their own proof of work, at a target the network allows, are kept.
+    /// Returns the hash of the block to request from peers so the orphan can connect.
+    pub fn add_orphan(&mut self, block: Block, peer: &str) -> Result<String, &'static str> {
+        if block.hash != block.calculate_hash() {
+            return Err(ValidationRule::Hash.message());
+        }
+        if !difficulty::within_pow_limit(&self.params, block.header.bits)
+            || !block.header.meets_target(&block.hash)
+        {
+            return Err(ValidationRule::ProofOfWork.message());
+        }
+        let hash = block.hash.clone();
+        self.orphans.insert(block, peer, Utc::now().timestamp());
+        self.orphans
+            .missing_ancestor(&hash)
+            .ok_or("Orphan was dropped")
+    }
+
+    // Checks a block against its parent in the block tree and connects it or stores it on a side branch
+    fn place_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        let invalid = |rule| ValidationError::new(&block, rule);
+        if self.blocks.contains_key(&block.hash) {
+            return Err(invalid(ValidationRule::Known));
+        }
+        let parent = self
+            .blocks
+            .get(&block.header.previous_hash)
+            .ok_or_else(|| invalid(ValidationRule::PreviousHash))?
+            .block
+            .hash
+            .clone();
+
+        let extends_tip = self.chain.last().map(|tip| &tip.hash) == Some(&parent);
+        if extends_tip {
+            self.check_header(&self.chain, &block).map_err(invalid)?;
+            return self.connect_valid_block(block);
+        }
+        if self.is_stale(&block) {
+            return Err(invalid(ValidationRule::ForkDepth));
+        }
+        self.check_header(&self.recent_ancestors(&parent), &block)
+            .map_err(invalid)?;
+        let hash = block.hash.clone();
+        self.index_block(&block);
+        if self.blocks[&hash].cumulative_work > self.total_work() {
+            self.reorganize(&hash)?;
+        }
+        Ok(())
+    }
+
+    // Checks everything about `block` that only depends on the branch it builds on, which ends in its parent
+    fn check_header(&self, branch: &[Block], block: &Block) -> Result<(), ValidationRule> {
+        let parent = branch.last().ok_or(ValidationRule::PreviousHash)?;
+        if block.header.index != parent.header.index + 1 {
+            return Err(ValidationRule::Index);
+        }
+        if block.header.previous_hash != parent.hash {
+            return Err(ValidationRule::PreviousHash);
+        }
+        if block.hash != block.calculate_hash() {
+            return Err(ValidationRule::Hash);
+        }
+        // A body repeating transactions can have the root of a valid one, and must not be mistaken for it
+        if block.header.merkle_root != block.calculate_merkle_root()
+            || block.has_duplicate_transactions()
+        {
+            return Err(ValidationRule::MerkleRoot);
+        }
+        let size: usize = block.transactions.iter().map(Transaction::size).sum();
+        if block.transactions.len() > MAX_BLOCK_TRANSACTIONS + 1 || size > MAX_BLOCK_SIZE {
+            return Err(ValidationRule::Size);
+        }
+        if !self.has_valid_proof(branch, block) {
+            return Err(ValidationRule::ProofOfWork);
+        }
+        if block.header.timestamp <= timestamps::median_time_past(branch)
+            || block.header.timestamp > self.adjusted_time() + self.params.max_future_drift
+        {
+            return Err(ValidationRule::Timestamp);
+        }
+        Ok(())
+    }
+
+    // Checks the transactions of `block` against the ledger state, as the next block of the active chain
+    fn check_transactions(&self, block: &Block) -> Result<(), ValidationRule> {
+        self.check_coinbase(block).ok_or(ValidationRule::Coinbase)?;
+        if !self.validate_transactions(&block.transactions[1..]) {
+            return Err(ValidationRule::Transactions);
+        }
+        Ok(())
+    }
+
+    // Connects a block extending the active chain after checking its transactions against the ledger state
+    fn connect_valid_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        self.check_transactions(&block)
+            .map_err(|rule| ValidationError::new(&block, rule))?;
+        self.connect_block(block);
+        Ok(())
+    }
+
+    // Switches the active chain to the branch ending in `tip`: the blocks after the fork point are disconnected and
+    // the blocks of the new branch are connected one by one. If one of them turns out to be invalid, it is forgotten
+    // along with its descendants and the old chain and pending transactions are restored. Otherwise transactions of
+    // the disconnected blocks that are not in the new branch go back to the pending transactions. Subscribers only
+    // hear about a reorganization that went through. In debug builds, the whole chain is checked again afterwards.
+    fn reorganize(&mut self, tip: &str) -> Result<(), ValidationError> {
+        // The new branch, from the tip back to where it leaves the active chain
+        let mut branch = Vec::new();
+        let mut next = self.blocks.get(tip);
+        while let Some(entry) = next.filter(|entry| !self.is_active(&entry.block)) {
+            branch.push(entry.block.clone());
+            next = self.blocks.get(&entry.block.header.previous_hash);
+        }
+        branch.reverse();
+        let fork = next
+            .ok_or_else(|| ValidationError {
+                index: 0,
+                hash: tip.to_string(),
+                rule: ValidationRule::PreviousHash,
+            })?
+            .block
+            .header
+            .index as usize
+            + 1;
+
+        let pending = self.pending_transactions.clone();
+        let disconnected = self.disconnect_to(fork);
+        let mut connected = Vec::new();
+        for block in branch {
+            if let Err(rule) = self.check_transactions(&block) {
+                self.forget_block(&block.hash);
+                self.disconnect_to(fork);
+                for block in disconnected {
+                    self.append_block(block);
+                }
+                self.pending_transactions = pending;
+                debug_assert!(self.is_chain_valid());
+                return Err(ValidationError::new(&block, rule));
+            }
+            connected.push((block.hash.clone(), block.header.index));
+            self.append_block(block);
+        }
+
+        let pending = std::mem::take(&mut self.pending_transactions);
+        let orphaned = disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter().skip(1))
+            .cloned();
+        for transaction in orphaned.chain(pending) {
+            // Anything already confirmed or pending is rejected as a replay or double spend
+            let _ = self.add_transaction(transaction);
+        }
+
+        for (hash, index) in &connected {
+            let _ = self.events.send(ChainEvent::BlockConnected {
+                hash: hash.clone(),
+                index: *index,
+            });
+        }
+        let _ = self.events.send(ChainEvent::Reorganized {
+            fork_point: self.chain[fork - 1].hash.clone(),
+            disconnected: disconnected
+                .iter()
+                .map(|block| block.hash.clone())
+                .collect(),
+            connected: connected.into_iter().map(|(hash, _)| hash).collect(),
+        });
+        self.prune_branches();
+        debug_assert!(self.is_chain_valid());
+        Ok(())
+    }
+
+    // Whether a block of the block tree is part of the active chain
+    fn is_active(&self, block: &Block) -> bool {
+        self.chain
+            .get(block.header.index as usize)
+            .is_some_and(|active| active.hash == block.hash)
+    }
+
+    // Whether a block is too far behind the active chain for a branch ending in it to ever be reorganized onto
+    fn is_stale(&self, block: &Block) -> bool {
+        block.header.index as usize + MAX_FORK_DEPTH < self.chain.len()
+    }
+
+    // Forgets the side branches that went stale, from their tip back to where they leave the active chain or
+    // meet another branch. This bounds the block tree to the recent blocks of the active chain and branches off it.
+    fn prune_branches(&mut self) {
+        let stale: Vec<String> = self
+            .tips
+            .iter()
+            .filter(|tip| {
+                let block = &self.blocks[*tip].block;
+                !self.is_active(block) && self.is_stale(block)
+            })
+            .cloned()
+            .collect();
+        for tip in stale {
+            let mut first = tip;
+            loop {
+                let parent = &self.blocks[&first].block.header.previous_hash;
+                let shared = self.children.get(parent).map_or(0, Vec::len) > 1;
+                match self.blocks.get(parent) {
+                    Some(entry) if !shared && !self.is_active(&entry.block) => {
+                        first = parent.clone()
+                    }
+                    _ => break,
+                }
+            }
+            self.forget_block(&first);
+        }
+    }
+
+    // The last blocks of the branch ending in `hash`, as many as checking a block on top of it looks at
+    fn recent_ancestors(&self, hash: &str) -> Vec<Block> {
+        let window = MEDIAN_TIME_SPAN.max(self.params.retarget_interval as usize);
+        let mut branch = Vec::new();
+        let mut next = self.blocks.get(hash);
+        while let Some(entry) = next.filter(|_| branch.len() < window) {
+            branch.push(entry.block.clone());
+            next = self.blocks.get(&entry.block.header.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    // Adds a block to the block tree, without touching the active chain
+    fn index_block(&mut self, block: &Block) {
+        if self.blocks.contains_key(&block.hash) {
+            return;
+        }
+        let parent_work = match self.blocks.get(&block.header.previous_hash) {
+            Some(parent) => {
+                let work = parent.cumulative_work;
+                self.children
+                    .entry(block.header.previous_hash.clone())
+                    .or_default()
+                    .push(block.hash.clone());
+                work
+            }
+            None => U256::zero(),
+        };
+        let entry = BlockEntry {
+            block: block.clone(),
+            cumulative_work: parent_work.saturating_add(block.header.work()),
+        };
+        self.tips.remove(&block.header.previous_hash);
+        self.tips.insert(block.hash.clone());
+        self.blocks.insert(block.hash.clone(), entry);
+    }
+
+    // Removes a block and everything built on it from the block tree, because it is invalid or went stale
+    fn forget_block(&mut self, hash: &str) {
+        let parent = match self.blocks.get(hash) {
+            Some(entry) => entry.block.header.previous_hash.clone(),
+            None => return,
+        };
+        let mut forgotten = vec![hash.to_string()];
+        while let Some(hash) = forgotten.pop() {
+            self.blocks.remove(&hash);
+            self.tips.remove(&hash);
+            forgotten.extend(self.children.remove(&hash).unwrap_or_default());
+        }
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+                if self.blocks.contains_key(&parent) {
+                    self.tips.insert(parent);
+                }
+            }
+        }
+    }
+
+    // Appends a block to the chain and the block tree, and lets subscribers know
+    fn connect_block(&mut self, block: Block) {
+        self.index_block(&block);
+        let event = ChainEvent::BlockConnected {
+            hash: block.hash.clone(),
+            index: block.header.index,
+        };
+        self.append_block(block);
+        self.prune_branches();
+        let _ = self.events.send(event);
+    }
+
+    // Appends a block to the chain, updating the ledger state and the transaction pool
+    fn append_block(&mut self, block: Block) {
+        self.apply_block(&block);
+        self.chain.push(block);
+
+        // Transactions that made it into the block, or conflict with one that did, are no longer pending
+        let (nonces, utxos) = (&self.nonces, &self.utxos);
+        self.pending_transactions
+            .retain(|tx| match self.ledger_mode {
+                LedgerMode::Account => tx.nonce >= nonces.get(&tx.sender).copied().unwrap_or(0),
+                LedgerMode::Utxo => tx.inputs.iter().all(|input| utxos.is_spendable(input)),
+            });
+    }
+
+    // Updates the ledger state with the transactions of a block
+    fn apply_block(&mut self, block: &Block) {
+        if block.header.index == 0 {
+            // Every coinbase of the genesis block is a genesis allocation
+            let allocated = block.transactions.iter().filter(|tx| tx.is_coinbase());
+            self.issued = Amount::checked_sum(allocated.map(|tx| tx.amount)).unwrap_or(MAX_SUPPLY);
+        } else if let Some(coinbase) = block.transactions.first().filter(|tx| tx.is_coinbase()) {
+            // Only a block breaking the coinbase rule could overflow, and `check_coinbase` rejects it before this
+            let fees = total_fees(&block.transactions).unwrap_or(MAX_SUPPLY);
+            self.issued = self
+                .issued
+                .checked_add(minted(coinbase, fees))
+                .unwrap_or(MAX_SUPPLY);
+        }
+        match self.ledger_mode {
+            LedgerMode::Account => self.apply_nonces(block),
+            LedgerMode::Utxo => {
+                let spent = self.utxos.apply_block(block);
+                self.spent.insert(block.hash.clone(), spent);
+            }
+        }
+    }
+
+    // Takes the blocks from `height` on off the active chain, undoing their changes to the ledger state, tip first
+    fn disconnect_to(&mut self, height: usize) -> Vec<Block> {
+        let disconnected = self.chain.split_off(height);
+        for block in disconnected.iter().rev() {
+            if let Some(coinbase) = block.transactions.first().filter(|tx| tx.is_coinbase()) {
+                let fees = total_fees(&block.transactions).unwrap_or(MAX_SUPPLY);
+                self.issued = self
+                    .issued
+                    .checked_sub(minted(coinbase, fees))
+                    .unwrap_or(Amount::ZERO);
+            }
+            match self.ledger_mode {
+                LedgerMode::Account => {
+                    // The nonce a transaction used is the one its sender expected before it
+                    for tx in block
+                        .transactions
+                        .iter()
+                        .rev()
+                        .filter(|tx| !tx.is_coinbase())
+                    {
+                        match tx.nonce {
+                            0 => self.nonces.remove(&tx.sender),
+                            nonce => self.nonces.insert(tx.sender, nonce),
+                        };
+                    }
+                }
+                LedgerMode::Utxo => {
+                    let spent = self.spent.remove(&block.hash).unwrap_or_default();
+                    self.utxos.undo_block(block, spent);
+                }
+            }
+        }
+        disconnected
+    }
+
+    /// Whether the chain follows every consensus rule, see `validate_chain`.
     pub fn is_chain_valid(&self) -> bool {
-        for (i, current_block) in self.chain[1..].iter().enumerate() {
-            let previous_block = &self.chain[i];
-            if current_block.hash != current_block.calculate_hash()
-                || current_block.previous_hash != previous_block.hash
-            {
-                return false;
+        self.validate_chain().is_ok()
+    }
+
+    /// Checks every consensus rule for every block of the chain, starting from the genesis block, by replaying the
+    /// chain onto an empty ledger. Fails with the first offending block and the rule it breaks.
+    pub fn validate_chain(&self) -> Result<(), ValidationError> {
+        let error = |index: usize, block: Option<&Block>, rule| ValidationError {
+            index,
+            hash: block.map(|block| block.hash.clone()).unwrap_or_default(),
+            rule,
+        };
+        let genesis = self.chain.first();
+        if genesis.map(|block| &block.hash) != Some(&self.params.genesis_block().hash)
+            || genesis.is_some_and(|block| block.hash != block.calculate_hash())
+        {
+            return Err(error(0, genesis, ValidationRule::Genesis));
+        }
+
+        let mut replay = Blockchain::empty(self.params.clone(), self.ledger_mode);
+        replay.append_block(self.chain[0].clone());
+        for (index, block) in self.chain.iter().enumerate().skip(1) {
+            self.check_header(&replay.chain, block)
+                .and_then(|_| replay.check_transactions(block))
+                .map_err(|rule| error(index, Some(block), rule))?;
+            replay.append_block(block.clone());
+        }
+        Ok(())
+    }
+
+    // Checks that a block starts with its one and only coinbase, paying out no more than the block's fees plus
+    // the subsidy allowed by the coins issued so far. Returns the new number of issued coins.
+    fn check_coinbase(&self, block: &Block) -> Option<Amount> {
+        let (coinbase, rest) = block.transactions.split_first()?;
+        if !coinbase.is_coinbase()
+            || rest.iter().any(Transaction::is_coinbase)
+            || coinbase.nonce != block.header.index as u64
+            || !coinbase.inputs.is_empty()
+            || !coinbase.change.is_zero()
+            || !coinbase.public_key.is_empty()
+            || !coinbase.signature.is_empty()
+        {
+            return None;
+        }
+        let minted = minted(coinbase, total_fees(rest)?);
+        if minted > reward::allowed_subsidy(&self.params, block.header.index, self.issued) {
+            return None;
+        }
+        self.issued.checked_add(minted)
+    }
+
+    /// Total work of the active chain up to and including the block at `index`, `None` past the tip.
+    pub fn cumulative_work(&self, index: usize) -> Option<U256> {
+        let block = self.chain.get(index)?;
+        self.blocks
+            .get(&block.hash)
+            .map(|entry| entry.cumulative_work)
+    }
+
+    /// Total work of the active chain, which decides between competing branches.
+    pub fn total_work(&self) -> U256 {
+        self.cumulative_work(self.chain.len().saturating_sub(1))
+            .unwrap_or_default()
+    }
+
+    /// Adds the blocks of a peer's chain to the block tree, reorganizing onto them if they have more work than the
+    /// active chain. Length alone doesn't count, or many blocks at an easy target could outweigh a few hard ones.
+    /// Returns whether the active chain changed.
+    pub fn replace_chain(&mut self, blocks: Vec<Block>) -> bool {
+        let tip = self.chain.last().map(|block| block.hash.clone());
+        for block in blocks {
+            if self.blocks.contains_key(&block.hash) {
+                continue;
+            }
+            // The rest of the chain builds on the block, so it can't be valid either
+            if self.accept_block(block).is_err() {
+                break;
             }
         }
-        true
+        self.chain.last().map(|block| block.hash.clone()) != tip
     }
 
-    pub fn from(&self, blocks: Vec<Block>) -> Self {
-        Blockchain {
-            chain: blocks,
-            difficulty: self.difficulty,
-            peers: self.peers.clone(),
-            pending_transactions: self.pending_transactions.clone(),
+    /// Hashes of blocks on the active chain from which a peer can tell where its chain and ours part, newest first:
+    /// the last ten blocks, then ever sparser ones back to the genesis block.
+    pub fn block_locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = self.chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.chain[height].hash.clone());
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
         }
     }
-    // Assuming you might have a method to calculate balance of an address
-    pub fn get_balance(&self, address: &str) -> f64 {
-        let mut balance = 0.0;
+
+    /// The blocks of the active chain after the first block of `locator` that is on it. A locator that only shares
+    /// the genesis block with us, which every peer does, gets everything after it.
+    pub fn blocks_after(&self, locator: &[String]) -> &[Block] {
+        let start = locator
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash))
+            .find(|entry| self.is_active(&entry.block))
+            .map_or(1, |entry| entry.block.header.index as usize + 1);
+        &self.chain[start.min(self.chain.len())..]
+    }
+
+    /// Calculates the confirmed balance of an address, from the UTXO set or by replaying every transaction in the chain.
+    /// Fails if the history would overflow the balance or take it below zero.
+    pub fn get_balance(&self, address: &Address) -> Result<Amount, CustomError> {
+        if self.ledger_mode == LedgerMode::Utxo {
+            return self
+                .utxos
+                .balance(address)
+                .ok_or_else(|| CustomError::new("Balance overflow"));
+        }
+
+        let mut balance = Amount::ZERO;
         for block in &self.chain {
             for trans in &block.transactions {
-                if trans.sender == address {
-                    balance -= trans.amount;
+                if &trans.sender == address && !trans.is_coinbase() {
+                    balance = trans
+                        .cost()
+                        .and_then(|cost| balance.checked_sub(cost))
+                        .ok_or_else(|| CustomError::new("Balance went negative"))?;
                 }
-                if trans.receiver == address {
-                    balance += trans.amount;
+                if &trans.receiver == address {
+                    balance = balance
+                        .checked_add(trans.amount)
+                        .ok_or_else(|| CustomError::new("Balance overflow"))?;
                 }
             }
         }
-        balance
+        Ok(balance)
+    }
+
+    /// Finds the confirmed transaction `txid` and proves its inclusion with the header of its block and a Merkle branch.
+    pub fn transaction_proof(&self, txid: &str) -> Option<(BlockHeader, MerkleProof)> {
+        self.chain.iter().find_map(|block| {
+            block
+                .merkle_proof(txid)
+                .map(|proof| (block.header.clone(), proof))
+        })
+    }
+
+    /// Checks a proof received from a peer: it has to place `txid` in a block of our active chain.
+    pub fn verify_transaction_proof(
+        &self,
+        txid: &str,
+        header: &BlockHeader,
+        proof: &MerkleProof,
+    ) -> bool {
+        let hash = header.hash();
+        let on_chain = self
+            .chain
+            .get(header.index as usize)
+            .is_some_and(|block| block.hash == hash);
+        on_chain && proof.txid == txid && header.verify_merkle_proof(proof)
     }
+
     // You can add other methods like mining, resolving conflicts, etc., here
 }
 
+// What the transactions picked for a block, or waiting in the mempool, commit to, so the next one can be checked
+// without going over them again
+#[derive(Default)]
+struct Commitments {
+    senders: HashMap<Address, (u64, Option<Amount>)>, // Number of transactions of each sender and what they cost
+    balances: HashMap<Address, Option<Amount>>,       // Confirmed balances looked up so far
+    inputs: HashSet<OutPoint>,                        // Outputs spent
+}
+
+impl Commitments {
+    fn commit(&mut self, transaction: &Transaction) {
+        let (count, spent) = self
+            .senders
+            .entry(transaction.sender)
+            .or_insert((0, Some(Amount::ZERO)));
+        *count += 1;
+        *spent = spent
+            .zip(transaction.cost())
+            .and_then(|(spent, cost)| spent.checked_add(cost));
+        self.inputs.extend(transaction.inputs.iter().cloned());
+    }
+}
+
+// Sum of the fees paid by the regular transactions of a block, `None` on overflow
+fn total_fees(transactions: &[Transaction]) -> Option<Amount> {
+    Amount::checked_sum(
+        transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.fee),
+    )
+}
+
+// Encoded size of a coinbase transaction, which every block template has to leave room for
+fn coinbase_size() -> usize {
+    Transaction::coinbase(Address::NULL, Amount::ZERO, 0).size()
+}
+
+fn new_events() -> broadcast::Sender<ChainEvent> {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+// New coins a coinbase creates: whatever it pays out beyond the fees it collects
+fn minted(coinbase: &Transaction, fees: Amount) -> Amount {
+    coinbase.amount.checked_sub(fees).unwrap_or(Amount::ZERO)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use secp256k1::SecretKey;
+
+    fn key(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn miner() -> Address {
+        Address::from_secret_key(&key(9))
+    }
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    fn transfer(from: &SecretKey, to: &SecretKey, amount: Amount, nonce: u64) -> Transaction {
+        transfer_with_fee(from, to, amount, Amount::ZERO, nonce)
+    }
+
+    fn transfer_with_fee(
+        from: &SecretKey,
+        to: &SecretKey,
+        amount: Amount,
+        fee: Amount,
+        nonce: u64,
+    ) -> Transaction {
+        let mut tx = Transaction::new(
+            Address::from_secret_key(from),
+            Address::from_secret_key(to),
+            amount,
+            fee,
+            nonce,
+        );
+        tx.sign(from);
+        tx
+    }
+
+    // Credits `amount` to `to` by mining a block whose coinbase pays it out
+    fn fund(blockchain: &mut Blockchain, to: &SecretKey, amount: Amount) -> OutPoint {
+        let previous = blockchain.chain.last().unwrap();
+        let index = previous.header.index + 1;
+        let coinbase = Transaction::coinbase(Address::from_secret_key(to), amount, index);
+        let timestamp = blockchain.next_timestamp();
+        let mut block = Block::new(index, timestamp, 0, previous.hash.clone(), vec![coinbase]);
+        block.header.bits = blockchain.get_difficulty();
+        block.mine_block();
+        let txid = block.transactions[0].hash();
+        blockchain.connect_block(block);
+        OutPoint { txid, vout: 0 }
+    }
+
+    // Mines a block holding `transactions` on top of the active chain and adds it like a block from a peer
+    fn add_block(
+        blockchain: &mut Blockchain,
+        transactions: Vec<Transaction>,
+        miner: &Address,
+    ) -> Result<(), &'static str> {
+        let mut block = blockchain.new_block(transactions, miner)?;
+        block.mine_block();
+        blockchain
+            .accept_block(block)
+            .map_err(|error| error.rule.message())
+    }
+
+    // A blockchain of the same network and ledger mode made up of `blocks`, connected without checking them
+    fn replay(blockchain: &Blockchain, blocks: Vec<Block>) -> Blockchain {
+        let mut replayed = Blockchain::empty(blockchain.params.clone(), blockchain.ledger_mode);
+        replayed.pending_transactions = blockchain.pending_transactions.clone();
+        for block in blocks {
+            replayed.connect_block(block);
+        }
+        replayed
+    }
 
     #[test]
     fn test_add_block() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
         let transactions = Vec::new(); // Define some transactions...
 
         let original_length = blockchain.chain.len();
-        blockchain.add_block(transactions);
+        add_block(&mut blockchain, transactions, &miner()).unwrap();
 
         assert_eq!(blockchain.chain.len(), original_length + 1);
+        assert!(blockchain.chain[1].transactions[0].is_coinbase());
+        assert_eq!(
+            blockchain.get_balance(&miner()).unwrap(),
+            blockchain.params().initial_subsidy
+        );
+        assert_eq!(blockchain.issued, blockchain.params().initial_subsidy);
     }
 
     #[test]
     fn test_is_chain_valid() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
         let transactions = Vec::new(); // Define some transactions...
 
-        blockchain.add_block(transactions);
+        add_block(&mut blockchain, transactions, &miner()).unwrap();
         assert!(blockchain.is_chain_valid());
 
         // Tamper with the chain
         blockchain.chain[1].hash = String::from("tampered");
         assert!(!blockchain.is_chain_valid());
+
+        // A block mined to an easier target than expected for its height is rejected
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        let block = &mut blockchain.chain[1];
+        block.header.bits = ChainParams::mainnet().pow_limit_bits;
+        block.mine_block();
+        assert!(!blockchain.is_chain_valid());
+    }
+
+    #[test]
+    fn test_coinbase_cannot_overpay() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        assert!(blockchain.is_chain_valid());
+
+        // Re-commit to the tampered body so only the coinbase rules can catch it
+        let block = &mut blockchain.chain[1];
+        block.transactions[0].amount = coins(51);
+        block.header.merkle_root = block.calculate_merkle_root();
+        block.mine_block();
+        assert!(!blockchain.is_chain_valid());
+
+        // A block without a coinbase is not valid either
+        let block = &mut blockchain.chain[1];
+        block.transactions.clear();
+        block.header.merkle_root = block.calculate_merkle_root();
+        block.mine_block();
+        assert!(!blockchain.is_chain_valid());
+
+        // And a body that doesn't match the header is caught by the Merkle root
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        blockchain.chain[1].transactions[0].amount = coins(1);
+        assert!(!blockchain.is_chain_valid());
+    }
+
+    #[test]
+    fn test_chain_params() {
+        let (alice, bob) = (key(1), key(2));
+        let mut params = ChainParams::regtest();
+        params.genesis_allocations = vec![TxOutput {
+            address: Address::from_secret_key(&alice),
+            amount: coins(1_000),
+        }];
+        let mut blockchain = Blockchain::new(params.clone(), LedgerMode::Account);
+        assert_eq!(blockchain.chain[0].hash, params.genesis_block().hash);
+        assert_eq!(blockchain.issued, coins(1_000));
+        assert_eq!(blockchain.get_difficulty(), params.initial_bits);
+
+        // Allocated coins can be spent right away, and count towards the supply
+        blockchain
+            .add_transaction(transfer(&alice, &bob, coins(400), 0))
+            .unwrap();
+        let transactions = blockchain.block_template();
+        add_block(&mut blockchain, transactions, &miner()).unwrap();
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&bob))
+                .unwrap(),
+            coins(400)
+        );
+        assert_eq!(
+            blockchain.issued,
+            coins(1_000).checked_add(params.initial_subsidy).unwrap()
+        );
+        assert!(blockchain.is_chain_valid());
+
+        // The blocks of one network are not valid on another
+        let mut mainnet = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        assert!(mainnet.accept_block(blockchain.chain[1].clone()).is_err());
+        assert!(!replay(&mainnet, blockchain.chain.clone()).is_chain_valid());
+    }
+
+    #[test]
+    fn test_pending_transactions_cannot_overspend() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let (alice, bob) = (key(1), key(2));
+        fund(&mut blockchain, &alice, coins(10));
+
+        let first = transfer(&alice, &bob, coins(6), 0);
+        assert!(blockchain.add_transaction(first.clone()).is_ok());
+        // The same transaction again, and a second one that only fits the confirmed balance
+        assert!(blockchain.add_transaction(first).is_err());
+        assert!(blockchain
+            .add_transaction(transfer(&alice, &bob, coins(6), 1))
+            .is_err());
+        assert!(blockchain
+            .add_transaction(transfer(&alice, &bob, coins(4), 1))
+            .is_ok());
+        // Bob has nothing confirmed yet
+        assert!(blockchain
+            .add_transaction(transfer(&bob, &alice, coins(1), 0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_block_cannot_overspend() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let (alice, bob) = (key(1), key(2));
+        fund(&mut blockchain, &alice, coins(10));
+
+        let overdraft = vec![
+            transfer(&alice, &bob, coins(6), 0),
+            transfer(&alice, &bob, coins(5), 1),
+        ];
+        assert!(add_block(&mut blockchain, overdraft, &miner()).is_err());
+
+        let double_spend = transfer(&alice, &bob, coins(3), 0);
+        assert!(add_block(
+            &mut blockchain,
+            vec![double_spend.clone(), double_spend],
+            &miner()
+        )
+        .is_err());
+
+        let length = blockchain.chain.len();
+        add_block(
+            &mut blockchain,
+            vec![transfer(&alice, &bob, coins(10), 0)],
+            &miner(),
+        )
+        .unwrap();
+        assert_eq!(blockchain.chain.len(), length + 1);
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&alice))
+                .unwrap(),
+            Amount::ZERO
+        );
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&bob))
+                .unwrap(),
+            coins(10)
+        );
+    }
+
+    #[test]
+    fn test_nonce_prevents_replay() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let (alice, bob) = (key(1), key(2));
+        fund(&mut blockchain, &alice, coins(10));
+
+        let payment = transfer(&alice, &bob, coins(1), 0);
+        blockchain.add_transaction(payment.clone()).unwrap();
+        add_block(&mut blockchain, vec![payment.clone()], &miner()).unwrap();
+        assert!(blockchain.pending_transactions.is_empty());
+        assert_eq!(blockchain.next_nonce(&Address::from_secret_key(&alice)), 1);
+
+        // Replaying the confirmed transaction fails, as does skipping a nonce
+        assert!(blockchain.add_transaction(payment.clone()).is_err());
+        assert!(add_block(&mut blockchain, vec![payment], &miner()).is_err());
+        assert!(blockchain
+            .add_transaction(transfer(&alice, &bob, coins(1), 2))
+            .is_err());
+        assert!(blockchain
+            .add_transaction(transfer(&alice, &bob, coins(1), 1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_utxo_ledger() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Utxo);
+        let (alice, bob) = (key(1), key(2));
+        let (alice_address, bob_address) = (
+            Address::from_secret_key(&alice),
+            Address::from_secret_key(&bob),
+        );
+        let funding = fund(&mut blockchain, &alice, coins(10));
+        assert!(blockchain.utxos.is_spendable(&funding));
+
+        let spend = |from: &SecretKey, inputs: Vec<OutPoint>, amount, change, fee| {
+            let mut tx = Transaction::spend(
+                Address::from_secret_key(from),
+                inputs,
+                bob_address,
+                amount,
+                change,
+                fee,
+            );
+            tx.sign(from);
+            tx
+        };
+
+        // Outputs worth more than the inputs, a fee that isn't the difference between inputs and outputs,
+        // and inputs owned by someone else are rejected
+        assert!(!blockchain.validate_transaction(&spend(
+            &alice,
+            vec![funding.clone()],
+            coins(8),
+            coins(4),
+            Amount::ZERO
+        )));
+        assert!(!blockchain.validate_transaction(&spend(
+            &alice,
+            vec![funding.clone()],
+            coins(6),
+            coins(3),
+            Amount::ZERO
+        )));
+        assert!(blockchain.validate_transaction(&spend(
+            &alice,
+            vec![funding.clone()],
+            coins(6),
+            coins(3),
+            coins(1)
+        )));
+        let mut stolen = spend(
+            &bob,
+            vec![funding.clone()],
+            coins(10),
+            Amount::ZERO,
+            Amount::ZERO,
+        );
+        stolen.receiver = alice_address;
+        stolen.sign(&bob);
+        assert!(!blockchain.validate_transaction(&stolen));
+
+        let payment = spend(
+            &alice,
+            vec![funding.clone()],
+            coins(6),
+            coins(4),
+            Amount::ZERO,
+        );
+        let double_spend = spend(
+            &alice,
+            vec![funding.clone()],
+            coins(5),
+            coins(5),
+            Amount::ZERO,
+        );
+        assert!(add_block(
+            &mut blockchain,
+            vec![payment.clone(), double_spend],
+            &miner()
+        )
+        .is_err());
+        add_block(&mut blockchain, vec![payment.clone()], &miner()).unwrap();
+
+        assert!(!blockchain.utxos.is_spendable(&funding));
+        let change = OutPoint {
+            txid: payment.hash(),
+            vout: 1,
+        };
+        assert_eq!(blockchain.utxos.get(&change).unwrap().amount, coins(4));
+        assert_eq!(blockchain.get_balance(&alice_address).unwrap(), coins(4));
+        assert_eq!(blockchain.get_balance(&bob_address).unwrap(), coins(6));
+        assert!(add_block(&mut blockchain, vec![payment.clone()], &miner()).is_err());
+
+        // Replacing the chain rebuilds the UTXO set from the received blocks
+        let rebuilt = replay(&blockchain, blockchain.chain.clone());
+        assert!(rebuilt.utxos.is_spendable(&change));
+
+        // Switching to a heavier branch without the payment undoes it
+        let mut fork = replay(&blockchain, blockchain.chain[..2].to_vec());
+        add_block(&mut fork, vec![], &miner()).unwrap();
+        add_block(&mut fork, vec![], &miner()).unwrap();
+        for block in &fork.chain[2..] {
+            blockchain.accept_block(block.clone()).unwrap();
+        }
+        assert_eq!(blockchain.chain.last().unwrap().hash, fork.chain[3].hash);
+        assert!(blockchain.utxos.is_spendable(&funding));
+        assert!(blockchain.utxos.get(&change).is_none());
+        assert_eq!(blockchain.get_balance(&alice_address).unwrap(), coins(10));
+        assert_eq!(blockchain.issued, fork.issued);
+        let pending: Vec<String> = blockchain
+            .pending_transactions
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(pending, vec![payment.hash()]);
+    }
+
+    #[test]
+    fn test_block_template_orders_by_fee_rate() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let (alice, bob, carol) = (key(1), key(2), key(3));
+        fund(&mut blockchain, &alice, coins(10));
+        fund(&mut blockchain, &carol, coins(10));
+        let fee = |units| Amount::from_units(units);
+
+        let alice_first = transfer_with_fee(&alice, &bob, coins(1), fee(10_000_000), 0);
+        // Best fee, but it has to wait for Alice's first transaction
+        let alice_second = transfer_with_fee(&alice, &bob, coins(1), fee(50_000_000), 1);
+        let carol_payment = transfer_with_fee(&carol, &bob, coins(1), fee(20_000_000), 0);
+        for tx in [&alice_first, &alice_second, &carol_payment] {
+            blockchain.add_transaction(tx.clone()).unwrap();
+        }
+
+        let template: Vec<String> = blockchain
+            .block_template()
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(
+            template,
+            vec![
+                carol_payment.hash(),
+                alice_first.hash(),
+                alice_second.hash()
+            ]
+        );
+
+        // The fees go to the miner on top of the subsidy
+        let mut block = blockchain.pending_block(&miner()).unwrap();
+        block.mine_block();
+        blockchain.accept_block(block).unwrap();
+        assert!(blockchain.pending_transactions.is_empty());
+        assert_eq!(
+            blockchain.get_balance(&miner()).unwrap(),
+            blockchain
+                .params()
+                .initial_subsidy
+                .checked_add(fee(80_000_000))
+                .unwrap()
+        );
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&alice))
+                .unwrap(),
+            coins(10)
+                .checked_sub(coins(2))
+                .and_then(|balance| balance.checked_sub(fee(60_000_000)))
+                .unwrap()
+        );
+        assert!(blockchain.is_chain_valid());
+    }
+
+    #[test]
+    fn test_fork_choice_by_work() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        let genesis_work = blockchain.chain[0].header.work();
+        assert_eq!(blockchain.cumulative_work(0), Some(genesis_work));
+        assert_eq!(
+            blockchain.cumulative_work(1),
+            Some(genesis_work + blockchain.chain[1].header.work())
+        );
+        assert_eq!(blockchain.cumulative_work(2), None);
+        assert_eq!(
+            blockchain.total_work(),
+            blockchain.cumulative_work(1).unwrap()
+        );
+
+        // A competing chain with the same work doesn't replace ours, one with more work does
+        let mut competitor = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut competitor, vec![], &Address::from_secret_key(&key(8))).unwrap();
+        assert!(!blockchain.replace_chain(competitor.chain.clone()));
+        add_block(&mut competitor, vec![], &Address::from_secret_key(&key(8))).unwrap();
+        assert!(blockchain.replace_chain(competitor.chain.clone()));
+        assert_eq!(blockchain.total_work(), competitor.total_work());
+
+        // Nor does an empty chain
+        assert!(!blockchain.replace_chain(vec![]));
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest(), LedgerMode::Account);
+        for _ in 0..29 {
+            add_block(&mut blockchain, vec![], &miner()).unwrap();
+        }
+        let hash = |height: usize| blockchain.chain[height].hash.clone();
+
+        // Dense at the tip, sparse further back, and always down to the genesis block
+        let locator = blockchain.block_locator();
+        let mut expected: Vec<String> = (20..30).rev().map(hash).collect();
+        expected.extend([18, 14, 6, 0].map(hash));
+        assert_eq!(locator, expected);
+        assert!(blockchain.blocks_after(&locator).is_empty());
+
+        // A peer gets what follows the newest block of its locator that is on our active chain
+        let behind = [String::from("unknown"), hash(4), hash(2)];
+        let blocks = blockchain.blocks_after(&behind);
+        assert_eq!(blocks.len(), 25);
+        assert_eq!(blocks[0].hash, hash(5));
+        assert_eq!(blockchain.blocks_after(&[]).len(), 29);
+    }
+
+    #[test]
+    fn test_stale_branches_are_pruned() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest(), LedgerMode::Account);
+        let other_miner = Address::from_secret_key(&key(8));
+        let mut fork = blockchain.clone();
+        add_block(&mut fork, vec![], &other_miner).unwrap();
+        add_block(&mut fork, vec![], &other_miner).unwrap();
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        blockchain.accept_block(fork.chain[1].clone()).unwrap();
+        assert_eq!(blockchain.tips.len(), 2);
+
+        // A branch stays around while it could still catch up
+        for _ in 0..MAX_FORK_DEPTH - 2 {
+            add_block(&mut blockchain, vec![], &miner()).unwrap();
+        }
+        assert!(blockchain.block(&fork.chain[1].hash).is_some());
+        blockchain.accept_block(fork.chain[2].clone()).unwrap();
+        assert_eq!(blockchain.tips.len(), 2);
+
+        // Once it is too far behind the whole branch is forgotten, and blocks that far back are no longer taken
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        assert!(blockchain.block(&fork.chain[1].hash).is_none());
+        assert!(blockchain.block(&fork.chain[2].hash).is_none());
+        assert_eq!(blockchain.tips.len(), 1);
+        assert_eq!(blockchain.children.len(), blockchain.chain.len() - 1);
+        let mut stale = blockchain.clone();
+        stale.disconnect_to(1);
+        add_block(&mut stale, vec![], &other_miner).unwrap();
+        assert_eq!(
+            blockchain
+                .accept_block(stale.chain[1].clone())
+                .unwrap_err()
+                .rule,
+            ValidationRule::ForkDepth
+        );
+    }
+
+    #[test]
+    fn test_reorganization() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let (alice, bob) = (key(1), key(2));
+        fund(&mut blockchain, &alice, coins(10));
+        let mut fork = replay(&blockchain, blockchain.chain.clone());
+        let mut events = blockchain.subscribe();
+
+        let payment = transfer(&alice, &bob, coins(3), 0);
+        add_block(&mut blockchain, vec![payment.clone()], &miner()).unwrap();
+        let replaced = blockchain.chain[2].hash.clone();
+        add_block(&mut fork, vec![], &miner()).unwrap();
+        add_block(&mut fork, vec![], &miner()).unwrap();
+
+        // A block without a known parent can't be placed in the tree
+        let mut stray = fork.chain[2].clone();
+        stray.header.previous_hash = "00".repeat(32);
+        assert!(blockchain.accept_block(stray).is_err());
+
+        // Our tip and the first block of the fork have the same work, so the fork is kept on a side branch
+        assert!(blockchain.accept_block(fork.chain[2].clone()).is_ok());
+        assert_eq!(blockchain.chain[2].hash, replaced);
+        assert_eq!(blockchain.tips.len(), 2);
+
+        // Once it has more work the chain switches over, and the payment goes back to the pending transactions
+        assert!(blockchain.accept_block(fork.chain[3].clone()).is_ok());
+        assert_eq!(blockchain.chain.len(), 4);
+        assert_eq!(blockchain.chain[3].hash, fork.chain[3].hash);
+        assert!(blockchain.block(&replaced).is_some());
+        assert!(blockchain.tips.contains(&replaced));
+        assert_eq!(blockchain.pending_transactions.len(), 1);
+        assert_eq!(blockchain.pending_transactions[0].hash(), payment.hash());
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&alice))
+                .unwrap(),
+            coins(10)
+        );
+        assert!(blockchain.is_chain_valid());
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received.last(),
+            Some(&ChainEvent::Reorganized {
+                fork_point: fork.chain[1].hash.clone(),
+                disconnected: vec![replaced],
+                connected: vec![fork.chain[2].hash.clone(), fork.chain[3].hash.clone()],
+            })
+        );
+    }
+
+    #[test]
+    fn test_failed_reorganization_restores_chain() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let alice = key(1);
+        fund(&mut blockchain, &alice, coins(10));
+        let mut fork = replay(&blockchain, blockchain.chain.clone());
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        add_block(&mut blockchain, vec![], &miner()).unwrap();
+        let tip = blockchain.chain[3].hash.clone();
+        let payment = transfer(&alice, &key(2), coins(1), 0);
+        blockchain.add_transaction(payment.clone()).unwrap();
+
+        // The fork confirms the payment, then pays its miner too much, and builds on that
+        add_block(&mut fork, vec![payment.clone()], &miner()).unwrap();
+        add_block(&mut fork, vec![], &miner()).unwrap();
+        let mut overpaid = fork.chain[3].clone();
+        overpaid.transactions[0].amount = overpaid.transactions[0]
+            .amount
+            .checked_add(coins(1))
+            .unwrap();
+        overpaid.header.merkle_root = overpaid.calculate_merkle_root();
+        overpaid.mine_block();
+        let coinbase = Transaction::coinbase(miner(), blockchain.params().initial_subsidy, 4);
+        let timestamp = overpaid.header.timestamp + 1;
+        let mut child = Block::new(4, timestamp, 0, overpaid.hash.clone(), vec![coinbase]);
+        child.header.bits = overpaid.header.bits;
+        child.mine_block();
+
+        let mut events = blockchain.subscribe();
+        blockchain.accept_block(fork.chain[2].clone()).unwrap();
+        blockchain.accept_block(overpaid.clone()).unwrap();
+        assert_eq!(blockchain.chain[3].hash, tip);
+
+        // Only the child gives the fork more work. The reorganization fails at the overpaying block.
+        assert_eq!(
+            blockchain.accept_block(child.clone()),
+            Err(ValidationError::new(&overpaid, ValidationRule::Coinbase))
+        );
+        assert_eq!(blockchain.chain.len(), 4);
+        assert_eq!(blockchain.chain[3].hash, tip);
+        let pending: Vec<String> = blockchain
+            .pending_transactions
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(pending, vec![payment.hash()]);
+        assert!(blockchain.is_chain_valid());
+
+        // The invalid block goes with everything built on it, and subscribers heard of nothing
+        assert!(blockchain.block(&overpaid.hash).is_none());
+        assert!(blockchain.block(&child.hash).is_none());
+        assert_eq!(
+            blockchain.tips,
+            HashSet::from([tip, fork.chain[2].hash.clone()])
+        );
+        assert!(blockchain.accept_block(child).is_err());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_orphan_connects_when_parent_arrives() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let mut peer = replay(&blockchain, blockchain.chain.clone());
+        add_block(&mut peer, vec![], &miner()).unwrap();
+        add_block(&mut peer, vec![], &miner()).unwrap();
+
+        // The second block arrives first, so it waits for the first one
+        assert!(blockchain.accept_block(peer.chain[2].clone()).is_err());
+        assert_eq!(
+            blockchain.add_orphan(peer.chain[2].clone(), "peer"),
+            Ok(peer.chain[1].hash.clone())
+        );
+        assert!(blockchain.orphans.contains(&peer.chain[2].hash));
+
+        // Blocks that don't carry their proof of work aren't kept
+        let mut unmined = peer.chain[2].clone();
+        while unmined.header.meets_target(&unmined.hash) {
+            unmined.header.nonce += 1;
+            unmined.hash = unmined.calculate_hash();
+        }
+        let mut rejected = vec![unmined.hash.clone()];
+        assert_eq!(
+            blockchain.add_orphan(unmined, "peer"),
+            Err(ValidationRule::ProofOfWork.message())
+        );
+        let mut mislabeled = peer.chain[2].clone();
+        mislabeled.hash = "00".repeat(32);
+        rejected.push(mislabeled.hash.clone());
+        assert_eq!(
+            blockchain.add_orphan(mislabeled, "peer"),
+            Err(ValidationRule::Hash.message())
+        );
+        let mut too_easy = peer.chain[2].clone();
+        too_easy.header.bits = 0x2100ffff;
+        too_easy.mine_block();
+        rejected.push(too_easy.hash.clone());
+        assert_eq!(
+            blockchain.add_orphan(too_easy, "peer"),
+            Err(ValidationRule::ProofOfWork.message())
+        );
+        assert!(!rejected
+            .iter()
+            .any(|hash| blockchain.orphans.contains(hash)));
+
+        blockchain.accept_block(peer.chain[1].clone()).unwrap();
+        assert!(!blockchain.orphans.contains(&peer.chain[2].hash));
+        assert_eq!(blockchain.chain.len(), 3);
+        assert_eq!(blockchain.chain[2].hash, peer.chain[2].hash);
+    }
+
+    #[test]
+    fn test_repeated_transactions_do_not_poison_block() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let alice = key(1);
+        fund(&mut blockchain, &alice, coins(10));
+        let mut peer = replay(&blockchain, blockchain.chain.clone());
+        let payments = vec![
+            transfer(&alice, &key(2), coins(1), 0),
+            transfer(&alice, &key(2), coins(1), 1),
+        ];
+        add_block(&mut peer, payments, &miner()).unwrap();
+        let block = peer.chain.last().unwrap().clone();
+
+        // Repeating the last transaction keeps the Merkle root and so the block hash
+        let mut mutated = block.clone();
+        mutated
+            .transactions
+            .push(block.transactions.last().unwrap().clone());
+        assert_eq!(mutated.calculate_merkle_root(), block.header.merkle_root);
+        assert_eq!(
+            blockchain.accept_block(mutated).unwrap_err().rule,
+            ValidationRule::MerkleRoot
+        );
+
+        // The genuine block with the same hash is still accepted
+        blockchain.accept_block(block.clone()).unwrap();
+        assert_eq!(blockchain.chain.last().unwrap().hash, block.hash);
+    }
+
+    #[test]
+    fn test_verify_transaction_proof() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let alice = key(1);
+        fund(&mut blockchain, &alice, coins(10));
+        let payment = transfer(&alice, &key(2), coins(1), 0);
+        add_block(&mut blockchain, vec![payment.clone()], &miner()).unwrap();
+
+        let (header, proof) = blockchain.transaction_proof(&payment.hash()).unwrap();
+        assert!(blockchain.verify_transaction_proof(&payment.hash(), &header, &proof));
+        assert!(!blockchain.verify_transaction_proof(&"00".repeat(32), &header, &proof));
+
+        // A header mined at the easiest target around a made-up transaction isn't on our chain
+        let fake = transfer(&alice, &key(3), coins(5), 1);
+        let mut block = Block::new(
+            header.index,
+            0,
+            0,
+            header.previous_hash.clone(),
+            vec![fake.clone()],
+        );
+        block.header.bits = blockchain.params().pow_limit_bits;
+        block.mine_block();
+        let fake_proof = block.merkle_proof(&fake.hash()).unwrap();
+        assert!(block.header.verify_merkle_proof(&fake_proof));
+        assert!(!blockchain.verify_transaction_proof(&fake.hash(), &block.header, &fake_proof));
+    }
+
+    #[test]
+    fn test_accept_block() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        let mut peer = replay(&blockchain, blockchain.chain.clone());
+        add_block(&mut peer, vec![], &Address::from_secret_key(&key(8))).unwrap();
+        let block = peer.chain[1].clone();
+
+        // Tampered with after mining. The error names the block and the rule it breaks.
+        let mut tampered = block.clone();
+        tampered.transactions[0].receiver = miner();
+        let err = blockchain.accept_block(tampered).unwrap_err();
+        assert_eq!((err.index, err.rule), (1, ValidationRule::MerkleRoot));
+        assert_eq!(err.hash, block.hash);
+
+        // Mined, but too far in the future
+        let mut future = block.clone();
+        future.header.timestamp =
+            Utc::now().timestamp() + ChainParams::mainnet().max_future_drift + 60;
+        future.mine_block();
+        assert_eq!(
+            blockchain.accept_block(future).unwrap_err().rule,
+            ValidationRule::Timestamp
+        );
+
+        // Mined, but out of place
+        let mut misplaced = block.clone();
+        misplaced.header.index = 2;
+        misplaced.mine_block();
+        assert_eq!(
+            blockchain.accept_block(misplaced).unwrap_err().rule,
+            ValidationRule::Index
+        );
+
+        // The peer's block is appended as is, reward and all
+        blockchain.accept_block(block.clone()).unwrap();
+        assert_eq!(blockchain.chain.len(), 2);
+        assert_eq!(blockchain.chain[1].hash, block.hash);
+        assert_eq!(
+            blockchain
+                .get_balance(&Address::from_secret_key(&key(8)))
+                .unwrap(),
+            blockchain.params().initial_subsidy
+        );
+        assert_eq!(
+            blockchain.accept_block(block).unwrap_err().rule,
+            ValidationRule::Known
+        );
+    }
+
+    #[test]
+    fn test_validation_rules() {
+        let mut valid = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        add_block(&mut valid, vec![], &miner()).unwrap();
+        assert_eq!(valid.validate_chain(), Ok(()));
+
+        // Breaks the last block of a copy of the valid chain, re-mining it unless `remine` is false
+        let broken = |remine: bool, modify: &dyn Fn(&mut Block)| {
+            let mut blockchain = valid.clone();
+            let block = blockchain.chain.last_mut().unwrap();
+            modify(block);
+            if remine {
+                block.mine_block();
+            }
+            blockchain.validate_chain().unwrap_err()
+        };
+
+        let error = broken(false, &|block| block.hash = "00".repeat(32));
+        assert_eq!(error.rule, ValidationRule::Hash);
+        assert_eq!((error.index, error.hash), (1, "00".repeat(32)));
+
+        let mut blockchain = valid.clone();
+        blockchain.chain[0].header.timestamp = 1;
+        blockchain.chain[0].hash = blockchain.chain[0].calculate_hash();
+        assert_eq!(
+            blockchain.validate_chain().unwrap_err().rule,
+            ValidationRule::Genesis
+        );
+        assert_eq!(
+            Blockchain::empty(ChainParams::mainnet(), LedgerMode::Account)
+                .validate_chain()
+                .unwrap_err()
+                .rule,
+            ValidationRule::Genesis
+        );
+
+        let rule = broken(true, &|block| block.header.index = 2).rule;
+        assert_eq!(rule, ValidationRule::Index);
+        let rule = broken(true, &|block| block.header.previous_hash = "00".repeat(32)).rule;
+        assert_eq!(rule, ValidationRule::PreviousHash);
+        let rule = broken(true, &|block| block.transactions[0].amount = coins(1)).rule;
+        assert_eq!(rule, ValidationRule::MerkleRoot);
+
+        // Recommits to the body, so that only the rules about the transactions themselves can catch a change
+        let recommit = |block: &mut Block| block.header.merkle_root = block.calculate_merkle_root();
+        let rule = broken(true, &|block| {
+            let coinbase = block.transactions[0].clone();
+            block.transactions = (0..MAX_BLOCK_TRANSACTIONS as u64 + 2)
+                .map(|nonce| Transaction {
+                    nonce,
+                    ..coinbase.clone()
+                })
+                .collect();
+            recommit(block);
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Size);
+        // The coinbase counts towards the size limit like any other transaction
+        let rule = broken(true, &|block| {
+            let mut filler = transfer(&key(1), &key(2), coins(1), 0);
+            let padding = MAX_BLOCK_SIZE - coinbase_size() + 1 - filler.size();
+            filler.signature.push_str(&"0".repeat(padding));
+            assert_eq!(
+                block.transactions[0].size() + filler.size(),
+                MAX_BLOCK_SIZE + 1
+            );
+            block.transactions.push(filler);
+            recommit(block);
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Size);
+
+        let rule = broken(true, &|block| {
+            block.header.bits = ChainParams::mainnet().pow_limit_bits
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::ProofOfWork);
+        let rule = broken(true, &|block| {
+            block.header.timestamp =
+                Utc::now().timestamp() + ChainParams::mainnet().max_future_drift + 60
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Timestamp);
+        // Not after the median time past, which for the first block is the genesis timestamp
+        let rule = broken(true, &|block| block.header.timestamp = 0).rule;
+        assert_eq!(rule, ValidationRule::Timestamp);
+
+        let rule = broken(true, &|block| {
+            block.transactions[0].amount = coins(51);
+            recommit(block);
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Coinbase);
+        // Nobody signs a coinbase, so there is nothing to fill in that would change its hash
+        let rule = broken(true, &|block| {
+            block.transactions[0].signature = String::from("00");
+            recommit(block);
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Coinbase);
+
+        // Alice has nothing to send
+        let rule = broken(true, &|block| {
+            block
+                .transactions
+                .push(transfer(&key(1), &key(2), coins(1), 0));
+            recommit(block);
+        })
+        .rule;
+        assert_eq!(rule, ValidationRule::Transactions);
     }
 
     // Add more tests for the blockchain...
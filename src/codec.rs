@@ -0,0 +1,155 @@
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{custom_error::CustomError, messages::Message};
+
+// Every message on the wire is a frame: a fixed header followed by the JSON encoded message.
+// The header holds the network magic, the payload length as a little-endian u32 and the first four bytes
+// of the double SHA-256 of the payload, so a reader always knows where a message ends and can tell
+// a corrupted or foreign one from a valid one before parsing it.
+pub const HEADER_SIZE: usize = 12;
+// Largest payload we accept, big enough for a page of blocks during sync and for a block of the largest size
+pub const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+// Largest payload we accept before the handshake is over, when all a peer may send is its version
+pub const MAX_HANDSHAKE_SIZE: usize = 4 * 1024;
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(&Sha256::digest(payload));
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Encodes a message into a frame for the network identified by `magic`.
+pub fn encode_frame(magic: [u8; 4], message: &Message) -> Result<Vec<u8>, CustomError> {
+    let payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(CustomError::new("Message too large"));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&magic);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Writes a single framed message.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    magic: [u8; 4],
+    message: &Message,
+) -> Result<(), CustomError> {
+    let frame = encode_frame(magic, message)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a single framed message, however it was split up on the way. Frames from another network,
+/// frames announcing more than `MAX_MESSAGE_SIZE` bytes and frames whose checksum doesn't match are rejected.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+) -> Result<Message, CustomError> {
+    read_limited_frame(reader, magic, MAX_MESSAGE_SIZE).await
+}
+
+/// Reads a single framed message like `read_frame`, rejecting frames that announce more than `max_size` bytes.
+pub async fn read_limited_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+    max_size: usize,
+) -> Result<Message, CustomError> {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    if header[0..4] != magic {
+        return Err(CustomError::new("Message is from another network"));
+    }
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    // Checked before allocating, so a peer can't make us reserve arbitrary amounts of memory
+    if length > max_size {
+        return Err(CustomError::new("Message too large"));
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    if header[8..12] != checksum(&payload) {
+        return Err(CustomError::new("Message checksum mismatch"));
+    }
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use tokio::io::duplex;
+
+    const MAGIC: [u8; 4] = *b"RBCr";
+
+    fn large_message() -> Message {
+        // Several KiB, too much for a single read
+        let blocks = (0..50)
+            .map(|index| Block::new(index, 0, 0, String::from("0"), vec![]))
+            .collect();
+        Message::SendBlocks(blocks)
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        // A tiny pipe forces every frame to be split into many reads and writes
+        let (mut client, mut server) = duplex(64);
+        let message = large_message();
+        let frame = encode_frame(MAGIC, &message).unwrap();
+        assert!(frame.len() > 1024);
+
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, MAGIC, &message).await.unwrap();
+            write_frame(&mut client, MAGIC, &Message::GetAddr)
+                .await
+                .unwrap();
+        });
+        match read_frame(&mut server, MAGIC).await.unwrap() {
+            Message::SendBlocks(blocks) => assert_eq!(blocks.len(), 50),
+            _ => panic!("Wrong message"),
+        }
+        assert!(matches!(
+            read_frame(&mut server, MAGIC).await.unwrap(),
+            Message::GetAddr
+        ));
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_frames() {
+        let frame = encode_frame(MAGIC, &Message::GetAddr).unwrap();
+
+        // Another network
+        assert!(read_frame(&mut &frame[..], *b"RBCm").await.is_err());
+
+        // Corrupted payload
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(read_frame(&mut &corrupted[..], MAGIC).await.is_err());
+
+        // Announcing more than the limit
+        let mut oversized = frame.clone();
+        oversized[4..8].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        assert!(read_frame(&mut &oversized[..], MAGIC).await.is_err());
+
+        // Fine once the handshake is over, but not before
+        let large = encode_frame(MAGIC, &large_message()).unwrap();
+        assert!(
+            read_limited_frame(&mut &large[..], MAGIC, MAX_HANDSHAKE_SIZE)
+                .await
+                .is_err()
+        );
+        assert!(read_frame(&mut &large[..], MAGIC).await.is_ok());
+
+        // Cut off before the end
+        assert!(read_frame(&mut &frame[..frame.len() - 1], MAGIC)
+            .await
+            .is_err());
+
+        assert!(read_frame(&mut &frame[..], MAGIC).await.is_ok());
+    }
+}
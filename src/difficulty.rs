@@ -0,0 +1,258 @@
+use primitive_types::{U256, U512};
+
+use crate::{block::Block, params::ChainParams};
+
+// Targets are stored in headers in the compact "bits" form: the top byte is the length of the target in bytes
+// and the low three bytes are its most significant digits, so target = mantissa * 256^(length - 3).
+
+// Limits how far a single retarget can move the target, in either direction
+pub const MAX_ADJUSTMENT: i64 = 4;
+
+/// Expands compact bits into the full 256-bit target. Negative, zero and overflowing targets are `None`.
+pub fn target_from_bits(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+    if mantissa == 0 || bits & 0x0080_0000 != 0 {
+        return None;
+    }
+    if size <= 3 {
+        let target = mantissa >> (8 * (3 - size));
+        return (target != 0).then(|| U256::from(target));
+    }
+    let shift = 8 * (size - 3) as usize;
+    // Every significant bit of the mantissa has to fit in 256 bits
+    if shift + (32 - mantissa.leading_zeros() as usize) > 256 {
+        return None;
+    }
+    Some(U256::from(mantissa) << shift)
+}
+
+/// Compresses a target into compact bits, rounding it down to three significant bytes.
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8) as u32;
+    let mut mantissa = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3) as usize)).low_u32()
+    };
+    // The top bit of the mantissa is a sign bit, so move a set one into the next byte
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | size << 24
+}
+
+/// Expected number of hashes needed to find a block meeting the target encoded by `bits`, i.e. 2^256 / (target + 1).
+/// Invalid bits represent no work.
+pub fn block_work(bits: u32) -> U256 {
+    match target_from_bits(bits) {
+        // 2^256 doesn't fit, but 2^256 / (target + 1) == (2^256 - target - 1) / (target + 1) + 1
+        Some(target) => (!target / (target + 1)) + 1,
+        None => U256::zero(),
+    }
+}
+
+/// Whether `bits` encode a valid target that is no easier than the PoW limit of the network.
+pub fn within_pow_limit(params: &ChainParams, bits: u32) -> bool {
+    target_from_bits(bits).is_some_and(|target| target <= params.pow_limit())
+}
+
+/// Checks a hex block hash, read as a big-endian 256-bit number, against the target encoded by `bits`.
+pub fn hash_meets_target(hash: &str, bits: u32) -> bool {
+    let target = match target_from_bits(bits) {
+        Some(target) => target,
+        None => return false,
+    };
+    match hex::decode(hash) {
+        Ok(bytes) if bytes.len() == 32 => U256::from_big_endian(&bytes) <= target,
+        _ => false,
+    }
+}
+
+/// Target after a window of blocks took `actual` seconds to mine where `expected` were planned,
+/// scaled by `actual / expected` at full precision and kept within `MAX_ADJUSTMENT` and the PoW limit.
+pub fn retarget(params: &ChainParams, bits: u32, actual: i64, expected: i64) -> u32 {
+    let limit = params.pow_limit();
+    let target = target_from_bits(bits).unwrap_or(limit);
+    let expected = expected.max(1);
+    // An interval shorter than `MAX_ADJUSTMENT` seconds must still not let the target drop to zero
+    let actual = actual.clamp(
+        (expected / MAX_ADJUSTMENT).max(1),
+        expected * MAX_ADJUSTMENT,
+    );
+
+    let scaled = U512::from(target) * U512::from(actual as u64) / U512::from(expected as u64);
+    let target = U256::try_from(scaled).unwrap_or(limit).min(limit);
+    bits_from_target(target)
+}
+
+/// Compact target the next block on top of `chain` has to meet. It stays the same within a retarget interval,
+/// and at the start of each interval it moves towards the target block time using the timestamps of the interval before.
+/// Heights come from the block headers, so `chain` only has to hold the last `retarget_interval` blocks.
+pub fn next_difficulty(params: &ChainParams, chain: &[Block]) -> u32 {
+    let last = match chain.last() {
+        Some(block) => block,
+        None => return params.initial_bits,
+    };
+    let height = last.header.index + 1;
+    let interval = params.retarget_interval;
+    if interval == 0 || !height.is_multiple_of(interval) {
+        return last.header.bits;
+    }
+
+    // The genesis timestamp is fixed rather than mined, so the first window starts after it
+    let first = height.saturating_sub(interval).max(1);
+    let blocks = (height - 1 - first) as i64;
+    if blocks == 0 {
+        return last.header.bits;
+    }
+    let start = height - chain.len() as u32; // Height of the first block of `chain`
+    let actual = last.header.timestamp - chain[(first - start) as usize].header.timestamp;
+    retarget(
+        params,
+        last.header.bits,
+        actual,
+        blocks * params.target_block_time,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A chain whose blocks after genesis were mined `spacing` seconds apart
+    fn chain(length: u32, spacing: i64) -> Vec<Block> {
+        (0..length)
+            .map(|index| Block::new(index, index as i64 * spacing, 0, String::from("0"), vec![]))
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_bits() {
+        let params = ChainParams::mainnet();
+        // Bitcoin's genesis target
+        let target = U256::from(0xffff) << 208;
+        assert_eq!(target_from_bits(0x1d00ffff), Some(target));
+        assert_eq!(bits_from_target(target), 0x1d00ffff);
+
+        assert_eq!(target_from_bits(0x03123456), Some(U256::from(0x123456)));
+        assert_eq!(target_from_bits(0x02123400), Some(U256::from(0x1234)));
+        assert_eq!(bits_from_target(U256::from(0x80)), 0x02008000);
+        assert_eq!(target_from_bits(0x02008000), Some(U256::from(0x80)));
+
+        // Negative, zero and too large targets
+        assert_eq!(target_from_bits(0x04923456), None);
+        assert_eq!(target_from_bits(0x01003456), None);
+        assert_eq!(target_from_bits(0x21010000), None);
+        assert_eq!(
+            target_from_bits(0x2000ffff),
+            target_from_bits(params.pow_limit_bits)
+        );
+    }
+
+    #[test]
+    fn test_hash_meets_target() {
+        let params = ChainParams::mainnet();
+        let hash = |prefix: &str| prefix.to_string() + &"ff".repeat(32 - prefix.len() / 2);
+        // The initial target lets through hashes starting with two zero bytes, and a bit more
+        assert!(hash_meets_target(
+            &hex::encode([0; 32]),
+            params.initial_bits
+        ));
+        assert!(hash_meets_target(&hash("0000fffe"), params.initial_bits));
+        assert!(!hash_meets_target(&hash("0001"), params.initial_bits));
+        assert!(hash_meets_target(&hash("00fffe"), params.pow_limit_bits));
+        assert!(!hash_meets_target(&hash("01"), params.pow_limit_bits));
+        assert!(!hash_meets_target("0000", params.initial_bits));
+        assert!(!hash_meets_target(&hex::encode([0; 32]), 0x04923456));
+    }
+
+    #[test]
+    fn test_block_work() {
+        let params = ChainParams::mainnet();
+        assert_eq!(block_work(0x1d00ffff), U256::from(0x1_0001_0001u64));
+        assert_eq!(block_work(params.initial_bits), U256::from(0x1_0001));
+        // Halving the target doubles the work
+        let half = bits_from_target(target_from_bits(params.initial_bits).unwrap() / 2);
+        assert_eq!(block_work(half), U256::from(0x2_0002));
+        assert_eq!(block_work(0x04923456), U256::zero());
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = ChainParams::mainnet();
+        let initial = target_from_bits(params.initial_bits).unwrap();
+        assert_eq!(
+            retarget(&params, params.initial_bits, 100, 100),
+            params.initial_bits
+        );
+        // Blocks twice as slow as planned double the target, twice as fast halve it
+        assert_eq!(
+            target_from_bits(retarget(&params, params.initial_bits, 200, 100)),
+            Some(initial * 2)
+        );
+        assert_eq!(
+            target_from_bits(retarget(&params, params.initial_bits, 50, 100)),
+            Some(initial / 2)
+        );
+        // Small deviations make small adjustments
+        let slightly_harder =
+            target_from_bits(retarget(&params, params.initial_bits, 90, 100)).unwrap();
+        assert!(slightly_harder < initial && slightly_harder > initial * 8 / 10);
+
+        // Adjustments are clamped, and never go past the PoW limit
+        assert_eq!(
+            retarget(&params, params.initial_bits, -5, 100),
+            retarget(&params, params.initial_bits, 25, 100)
+        );
+        assert_eq!(
+            retarget(&params, params.initial_bits, 10_000, 100),
+            retarget(&params, params.initial_bits, 400, 100)
+        );
+        assert_eq!(
+            retarget(&params, params.pow_limit_bits, 400, 100),
+            params.pow_limit_bits
+        );
+        // However short the interval, the target stays above zero
+        let target = target_from_bits(retarget(&params, params.initial_bits, 0, 2)).unwrap();
+        assert_eq!(target, initial / 2);
+    }
+
+    #[test]
+    fn test_next_difficulty() {
+        let params = ChainParams::mainnet();
+        let interval = params.retarget_interval;
+        assert_eq!(next_difficulty(&params, &[]), params.initial_bits);
+
+        // No adjustment in the middle of an interval, however fast the blocks come
+        assert_eq!(
+            next_difficulty(&params, &chain(interval - 1, 0)),
+            params.initial_bits
+        );
+
+        assert_eq!(
+            next_difficulty(&params, &chain(interval, params.target_block_time)),
+            params.initial_bits
+        );
+        assert_eq!(
+            next_difficulty(&params, &chain(interval, params.target_block_time / 2)),
+            retarget(&params, params.initial_bits, 1, 2)
+        );
+        let long_chain = chain(2 * interval, params.target_block_time * 2);
+        assert_eq!(
+            next_difficulty(&params, &long_chain),
+            retarget(&params, params.initial_bits, 2, 1)
+        );
+        // The last interval is all it takes
+        assert_eq!(
+            next_difficulty(&params, &long_chain[interval as usize..]),
+            next_difficulty(&params, &long_chain)
+        );
+
+        // Blocks after a retarget keep the new target
+        let mut chain = chain(interval + 1, 1);
+        chain[interval as usize].header.bits = 0x1e00ffff;
+        assert_eq!(next_difficulty(&params, &chain), 0x1e00ffff);
+    }
+}
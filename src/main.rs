@@ -1,39 +1,163 @@
+mod address;
+mod address_book;
+mod amount;
 mod block;
 mod blockchain;
+mod codec;
 pub mod custom_error;
+mod difficulty;
+mod encoding;
+mod merkle;
 pub mod messages;
 mod networking;
-mod transaction; // Declare the modules
-
-use blockchain::Blockchain;
-use networking::try_connect_peer;
-
-use std::{env, sync::Arc};
-use tokio::{net::TcpStream, sync::Mutex, time::sleep, time::Duration};
-use transaction::Transaction;
+mod orphans;
+mod params;
+mod peers;
+mod reward;
+mod timestamps;
+mod transaction;
+mod utxo;
+mod validation; // Declare the modules
+
+use address::Address;
+use blockchain::{Blockchain, ChainEvent};
+use networking::Node;
+use params::ChainParams;
+use peers::PeerManager;
+use secp256k1::{rand, SecretKey};
+
+use chrono::Utc;
+use custom_error::CustomError;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+};
+use tokio::{sync::broadcast::error::RecvError, time::sleep, time::Duration};
+use utxo::LedgerMode;
 
 use crate::networking::connect_to_peers;
 
 #[tokio::main]
 async fn main() {
-    // Create a shared blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} [port_number]", args[0]);
+    if !(2..=6).contains(&args.len()) {
+        eprintln!(
+            "Usage: {} [port_number] [miner_address] [mainnet|testnet|regtest|params.json] [account|utxo] [external_address]",
+            args[0]
+        );
         return;
     }
 
+    // The network is one of the presets, or a private one described by a parameters file
+    let params = match args.get(3) {
+        Some(network) => match ChainParams::from_name(network) {
+            Some(params) => params,
+            None => match ChainParams::load(network) {
+                Ok(params) => params,
+                Err(err) => {
+                    eprintln!("Invalid chain parameters {}: {}", network, err);
+                    return;
+                }
+            },
+        },
+        None => ChainParams::mainnet(),
+    };
+    println!("Joining {}", params.network);
+    // Every node of a network has to track balances the same way, with accounts unless told otherwise
+    let ledger_mode = match args.get(4) {
+        Some(name) => match LedgerMode::from_name(name) {
+            Some(ledger_mode) => ledger_mode,
+            None => {
+                eprintln!("Unknown ledger mode {}", name);
+                return;
+            }
+        },
+        None => LedgerMode::Account,
+    };
+    // Block rewards go to the given address, or to a freshly generated key
+    let miner_address = match args.get(2) {
+        Some(address) => match address.parse::<Address>() {
+            Ok(address) => address,
+            Err(err) => {
+                eprintln!("Invalid miner address {}: {}", address, err);
+                return;
+            }
+        },
+        None => {
+            // The key stays in a file only we can read, and is reused on the next start
+            let key_file = format!("miner_{}_{}.key", params.network, args[1]);
+            match load_or_create_key(&key_file) {
+                Ok(secret_key) => {
+                    let address = Address::from_secret_key(&secret_key);
+                    println!("Mining to address {} (key in {})", address, key_file);
+                    address
+                }
+                Err(err) => {
+                    eprintln!("Failed to set up the miner key {}: {}", key_file, err);
+                    return;
+                }
+            }
+        }
+    };
+
+    // Nodes we learned about in earlier runs, so we don't depend on the seed nodes alone
+    let address_book = format!("peers_{}_{}.json", params.network, args[1]);
+    let mut peers = PeerManager::load(&address_book).unwrap_or_else(|err| {
+        eprintln!("Failed to load the address book {}: {}", address_book, err);
+        PeerManager::new()
+    });
+    let now = Utc::now().timestamp();
+    for seed in &params.seed_nodes {
+        peers.address_book_mut().add(seed, now);
+    }
+    // Peers are only told where to reach us if we know an address they can reach us at
+    if let Some(external_address) = args.get(5) {
+        if external_address.parse::<SocketAddr>().is_err() {
+            eprintln!("Invalid external address {}", external_address);
+            return;
+        }
+        peers.set_listen_address(external_address.clone());
+    }
+
+    // Create the node, whose blockchain and peers are shared by all its tasks
+    let node = Node::new(Blockchain::new(params, ledger_mode), peers, miner_address);
+
+    // Report every change to the active chain, however it came about
+    let mut events = node.blockchain.lock().await.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(ChainEvent::BlockConnected { hash, index }) => {
+                    println!("Block {} connected: {}", index, hash)
+                }
+                Ok(ChainEvent::Reorganized {
+                    fork_point,
+                    disconnected,
+                    connected,
+                }) => println!(
+                    "Reorganized after {}: {} blocks disconnected, {} connected",
+                    fork_point,
+                    disconnected.len(),
+                    connected.len()
+                ),
+                Err(RecvError::Lagged(missed)) => eprintln!("Missed {} chain events", missed),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
     let port = args[1].clone();
     let port_for_server = port.clone(); // Clone for the server
     let port_for_peers = port.clone(); // Clone for the peers
+    let node_for_peers = node.clone();
 
     // Start the server (this should keep running to listen for incoming connections)
-    let server_handle = tokio::spawn(networking::start_server(
-        port_for_server,
-        blockchain.clone(),
-    ));
+    let server_handle = tokio::spawn(networking::start_server(port_for_server, node.clone()));
+
+    // Mine blocks from new transactions in the background, broadcasting every block we find
+    let mining_handle = tokio::spawn(networking::mine(node.clone()));
 
     // Use a timer to periodically attempt connections to known peers
     let peer_connection_handle = tokio::spawn(async move {
@@ -41,63 +165,33 @@ async fn main() {
 
         loop {
             let current_node_address = format!("127.0.0.1:{}", port_for_peers.clone());
-            connect_to_peers(current_node_address).await;
+            connect_to_peers(current_node_address, node_for_peers.clone()).await;
             sleep(Duration::from_secs(PEER_REFRESH_INTERVAL)).await;
         }
     });
 
-    // Await both tasks to completion (they likely won't complete under normal circumstances unless there's an error)
-    let _ = tokio::try_join!(server_handle, peer_connection_handle);
+    // Await all tasks to completion (they likely won't complete under normal circumstances unless there's an error)
+    let _ = tokio::try_join!(server_handle, mining_handle, peer_connection_handle);
 }
 
-async fn broadcast_transaction(transaction: &Transaction, blockchain: Arc<Mutex<Blockchain>>) {
-    let blockchain_guard = blockchain.lock().await;
-    for peer_address in &blockchain_guard.peers {
-        if let Ok(mut peer_stream) = TcpStream::connect(peer_address).await {
-            networking::write_message(
-                &mut peer_stream,
-                &messages::Message::NewTransaction(transaction.clone()),
-            )
-            .await
-            .unwrap();
+// Reads the hex encoded secret key from `path`, or generates one and writes it there, readable by the owner only
+fn load_or_create_key(path: &str) -> Result<SecretKey, CustomError> {
+    match fs::read_to_string(path) {
+        Ok(hex_key) => {
+            let bytes = hex::decode(hex_key.trim())
+                .map_err(|_| CustomError::new("Key file doesn't hold a hex encoded key"))?;
+            SecretKey::from_slice(&bytes).map_err(|_| CustomError::new("Invalid secret key"))
         }
-    }
-}
-
-async fn broadcast_mined_block(block: &block::Block, blockchain: Arc<Mutex<Blockchain>>) {
-    let blockchain_guard = blockchain.lock().await;
-    for peer_address in &blockchain_guard.peers {
-        if let Ok(mut peer_stream) = TcpStream::connect(peer_address).await {
-            networking::write_message(
-                &mut peer_stream,
-                &messages::Message::BroadcastBlock(block.clone()),
-            )
-            .await
-            .unwrap();
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let secret_key = SecretKey::new(&mut rand::thread_rng());
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(path)?;
+            file.write_all(hex::encode(secret_key.secret_bytes()).as_bytes())?;
+            Ok(secret_key)
         }
+        Err(err) => Err(err.into()),
     }
 }
-
-const SEED_NODES: [&str; 2] = ["127.0.0.1:8000", "127.0.0.1:8001"];
-
-// When a node starts:
-async fn start_node(port: &str, blockchain: Arc<Mutex<Blockchain>>) {
-    // Start the server to listen for incoming connections.
-    let server_handle = tokio::spawn(networking::start_server(
-        port.to_string(),
-        blockchain.clone(),
-    ));
-
-    // Try connecting to seed nodes
-    for seed in SEED_NODES.iter() {
-        // Don't connect to yourself
-        if seed != &format!("127.0.0.1:{}", port) {
-            if let Err(err) = try_connect_peer(seed).await {
-                eprintln!("Failed to connect to seed node {}: {}", seed, err);
-            }
-        }
-    }
-
-    // Wait for the server to finish (it probably won't, since it should keep listening)
-    let _ = server_handle.await;
-}
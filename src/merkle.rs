@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Prefixed to every inner node so an inner node can never be passed off as a transaction id
+const NODE_TAG: u8 = 0x01;
+
+// Root of a block without transactions
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+pub fn hash_nodes(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Decodes a hex transaction id into a leaf, anything malformed becomes a leaf no real transaction can match
+pub fn leaf(txid: &str) -> [u8; 32] {
+    let mut leaf = [0xff; 32];
+    if let Ok(bytes) = hex::decode(txid) {
+        if bytes.len() == 32 {
+            leaf.copy_from_slice(&bytes);
+        }
+    }
+    leaf
+}
+
+/// Merkle root over a list of transaction ids. A level with an odd number of nodes pairs its last node with itself,
+/// which means a list that repeats its last ids has the same root as the list without them: `[a, b, c]` and
+/// `[a, b, c, c]` share a root. Lists with repeated ids have to be rejected, see `has_duplicates`.
+pub fn merkle_root(txids: &[String]) -> String {
+    if txids.is_empty() {
+        return hex::encode(EMPTY_ROOT);
+    }
+    let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| leaf(txid)).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_nodes(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    hex::encode(level[0])
+}
+
+/// Whether an id appears more than once. Any two lists with the same root differ by repeated ids, so a block
+/// without them is the only one its root can stand for.
+pub fn has_duplicates(txids: &[String]) -> bool {
+    let mut seen = HashSet::new();
+    !txids.iter().all(|txid| seen.insert(txid))
+}
+
+// Proves that a transaction is committed to by a Merkle root, without needing the other transactions
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub txid: String,
+    pub index: u32,          // Position of the transaction in the block
+    pub count: u32,          // Number of transactions in the block
+    pub branch: Vec<String>, // Hex encoded sibling hashes, from the leaf level up to just below the root
+}
+
+impl MerkleProof {
+    /// Recomputes the root from the transaction id and branch, and checks it against `merkle_root`.
+    /// The branch has to have the shape of a tree over `count` distinct ids: only the last node of an odd level is
+    /// paired with itself. Otherwise the copy of the last id that pads an odd level could be proven at a position
+    /// past the end of the block, as `[a, b, c]` and `[a, b, c, c]` share a root.
+    pub fn verify(&self, merkle_root: &str) -> bool {
+        if self.index >= self.count {
+            return false;
+        }
+        let mut hash = leaf(&self.txid);
+        let mut index = self.index;
+        let mut width = self.count; // Nodes on the current level
+        for sibling in &self.branch {
+            let sibling = leaf(sibling);
+            let pads_level = index ^ 1 >= width;
+            if pads_level != (sibling == hash) {
+                return false;
+            }
+            hash = if index.is_multiple_of(2) {
+                hash_nodes(&hash, &sibling)
+            } else {
+                hash_nodes(&sibling, &hash)
+            };
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+        // The branch has to reach the root, and every bit of the index has to be used up
+        width == 1 && index == 0 && hex::encode(hash) == merkle_root
+    }
+}
+
+/// Builds the proof that `txids[index]` is part of `merkle_root(txids)`.
+pub fn merkle_proof(txids: &[String], index: usize) -> Option<MerkleProof> {
+    let txid = txids.get(index)?.clone();
+    let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| leaf(txid)).collect();
+    let mut position = index;
+    let mut branch = Vec::new();
+    while level.len() > 1 {
+        // The last node of an odd level is its own sibling
+        let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+        branch.push(hex::encode(sibling));
+        level = level
+            .chunks(2)
+            .map(|pair| hash_nodes(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        position /= 2;
+    }
+    Some(MerkleProof {
+        txid,
+        index: index as u32,
+        count: txids.len() as u32,
+        branch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(seed: u8) -> String {
+        hex::encode([seed; 32])
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        assert_eq!(merkle_root(&[]), hex::encode(EMPTY_ROOT));
+        assert_eq!(merkle_root(&[txid(1)]), txid(1));
+
+        let pair = hash_nodes(&[1; 32], &[2; 32]);
+        assert_eq!(merkle_root(&[txid(1), txid(2)]), hex::encode(pair));
+
+        // The odd leaf out is paired with itself
+        let odd = hash_nodes(&[3; 32], &[3; 32]);
+        assert_eq!(
+            merkle_root(&[txid(1), txid(2), txid(3)]),
+            hex::encode(hash_nodes(&pair, &odd))
+        );
+        assert_ne!(
+            merkle_root(&[txid(1), txid(2), txid(3)]),
+            merkle_root(&[txid(2), txid(1), txid(3)])
+        );
+
+        // Repeating the odd leaf out doesn't change the root, which is why repeated ids are caught separately
+        let repeated = [txid(1), txid(2), txid(3), txid(3)];
+        assert_eq!(merkle_root(&repeated), merkle_root(&repeated[..3]));
+        assert!(has_duplicates(&repeated));
+        assert!(!has_duplicates(&repeated[..3]));
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        for count in 1..=7 {
+            let txids: Vec<String> = (1..=count).map(txid).collect();
+            let root = merkle_root(&txids);
+            for index in 0..txids.len() {
+                let proof = merkle_proof(&txids, index).unwrap();
+                assert!(proof.verify(&root), "{} of {}", index, count);
+
+                // A proof for a different transaction or position fails
+                let mut forged = proof.clone();
+                forged.txid = txid(99);
+                assert!(!forged.verify(&root));
+                let mut moved = proof.clone();
+                moved.index += 1 << proof.branch.len();
+                assert!(!moved.verify(&root));
+            }
+        }
+        assert!(merkle_proof(&[txid(1)], 1).is_none());
+
+        // The copy of the last id that pads an odd level isn't a transaction of its own
+        let txids = [txid(1), txid(2), txid(3)];
+        let root = merkle_root(&txids);
+        let mut phantom = merkle_proof(&txids, 2).unwrap();
+        phantom.index = 3;
+        assert!(!phantom.verify(&root));
+        phantom.count = 4;
+        assert!(!phantom.verify(&root));
+        let mut miscounted = merkle_proof(&txids, 2).unwrap();
+        miscounted.count = 5;
+        assert!(!miscounted.verify(&root));
+    }
+}
@@ -1,14 +1,57 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{block::Block, transaction::Transaction};
+use crate::{
+    block::{Block, BlockHeader},
+    merkle::MerkleProof,
+    transaction::Transaction,
+};
+
+// Version of the protocol spoken by this node, raised whenever messages change incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+// Oldest version of the protocol we still talk to. Version 1 sent whole chains rather than pages of blocks.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+// Service flag of a node that keeps the full chain and serves blocks to its peers
+pub const NODE_NETWORK: u64 = 1;
+pub const USER_AGENT: &str = concat!("/rust-blockchain:", env!("CARGO_PKG_VERSION"), "/");
+
+// What a node tells about itself when a session starts
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    pub network: String,
+    pub genesis_hash: String, // Identifies the chain, nodes on different chains can't talk to each other
+    pub best_height: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub listen_address: Option<String>, // Where the node accepts connections, if it does
+    pub timestamp: i64,                 // The node's clock, which feeds our network-adjusted time
+}
 
 // Defines the different types of messages that can be sent over the network (e.g., requesting the blockchain, sending the blockchain, creating a new transaction)
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Message {
-    RequestBlockchain,
-    SendBlockchain(Vec<Block>),
+    // Opens the handshake every session starts with
+    Version(VersionInfo),
+    // Acknowledges a compatible `Version`, completing the handshake
+    Verack,
+    // Asks a peer for the blocks of its chain after the first of these hashes it has, see `Blockchain::block_locator`
+    RequestBlocks(Vec<String>),
+    // A page of the blocks a peer asked for with `RequestBlocks`, in chain order
+    SendBlocks(Vec<Block>),
     NewTransaction(Transaction),
     BroadcastTransaction(Transaction),
-    BroadcastBlock(Block)
+    BroadcastBlock(Block),
+    // Asks a peer to prove that the transaction with this id is confirmed
+    RequestTransactionProof(String),
+    // The transaction id and, if it is confirmed, the header of its block with the Merkle branch
+    SendTransactionProof(String, Option<(BlockHeader, MerkleProof)>),
+    // Asks a peer for the block with this hash, e.g. the missing parent of an orphan block
+    RequestBlock(String),
+    // A block requested with `RequestBlock`
+    SendBlock(Block),
+    // Asks a peer for the addresses of the nodes it knows of
+    GetAddr,
+    // Addresses of nodes, each with when it was last known to be up
+    Addr(Vec<(String, i64)>),
     // ... other message types
 }
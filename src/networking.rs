@@ -1,200 +1,789 @@
+use crate::address::Address;
+use crate::address_book::{self, MAX_ADDR_PER_MESSAGE};
+use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::codec;
 use crate::custom_error::CustomError;
-use crate::messages::Message;
+use crate::messages::{
+    Message, VersionInfo, MIN_PROTOCOL_VERSION, NODE_NETWORK, PROTOCOL_VERSION, USER_AGENT,
+};
+use crate::peers::{Direction, PeerManager, MAX_INBOUND};
 
-use once_cell::sync::Lazy;
-use std::{sync::Arc, collections::HashSet};
+use chrono::Utc;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    io::{AsyncRead, AsyncWrite},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{mpsc, Mutex, Notify, Semaphore},
+    task,
+    time::{sleep, timeout, Duration},
 };
 
-// Static list of initial seed nodes
-const SEED_NODES: &[&str] = &["127.0.0.1:8000", "127.0.0.1:8001"];
+// Number of messages waiting to be written to a peer before further ones are dropped
+const OUTBOUND_QUEUE: usize = 256;
+// Seconds to wait before reconnecting to a peer, doubled after every failed attempt up to the maximum
+const INITIAL_BACKOFF: u64 = 1;
+const MAX_BACKOFF: u64 = 64;
+// Seconds a peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: u64 = 10;
+// Failed attempts in a row after which we stop reconnecting to a peer and free its slot for another address
+const MAX_RECONNECTS: u32 = 3;
+// Most blocks sent in a single `SendBlocks`, and the most their encoding may take up so a page fits in a frame
+const MAX_BLOCKS_PER_MESSAGE: usize = 500;
+const MAX_PAGE_SIZE: usize = codec::MAX_MESSAGE_SIZE / 2;
 
-pub static PEERS: Lazy<Arc<Mutex<Vec<String>>>> = Lazy::new(|| {
-    let initial_peers = SEED_NODES.iter().map(|&s| s.to_string()).collect();
-    Arc::new(Mutex::new(initial_peers))
-});
+// Everything the tasks of a node share: its blockchain, its peers, the address its blocks reward and the signal
+// that starts mining a block. Cloning it is cheap and gives another handle to the same node.
+#[derive(Clone)]
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub peers: Arc<Mutex<PeerManager>>,
+    pub miner_address: Address,
+    mine: Arc<Notify>,
+}
 
-pub static ACTIVE_PEERS: Lazy<Arc<Mutex<HashSet<String>>>> = Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
+impl Node {
+    pub fn new(blockchain: Blockchain, peers: PeerManager, miner_address: Address) -> Self {
+        Node {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            peers: Arc::new(Mutex::new(peers)),
+            miner_address,
+            mine: Arc::new(Notify::new()),
+        }
+    }
 
-pub async fn add_peer(address: String) {
-    let mut peers = PEERS.lock().await;
-    if !peers.contains(&address) {
-        peers.push(address);
+    /// Queues a message for every peer we have a session with, except `except`.
+    pub async fn broadcast(&self, message: &Message, except: Option<&str>) {
+        self.peers.lock().await.broadcast(message, except);
     }
 }
 
-pub async fn get_peers() -> Vec<String> {
-    let peers: tokio::sync::MutexGuard<'_, Vec<String>> = PEERS.lock().await;
-    peers.clone()
-}
+// Tops up our outbound sessions from the address book, preferring addresses that worked recently and spreading
+// them over network groups. Each session is kept up by its own task from then on. Also saves the address book,
+// and catches up with the peer with the most blocks if it is ahead of us.
+pub async fn connect_to_peers(current_node_address: String, node: Node) {
+    let now = Utc::now().timestamp();
+    let height = node.blockchain.lock().await.chain.len() as u32 - 1;
+    let mut peers = node.peers.lock().await;
+    peers.address_book_mut().expire(now);
+    if let Err(err) = peers.address_book().save() {
+        eprintln!("Failed to save the address book: {}", err);
+    }
 
-pub async fn connect_to_peers(current_node_address: String) {
-    let peers = get_peers().await;
-    let active_peers = ACTIVE_PEERS.lock().await;
+    if let Some(best) = peers.best_peer(height).cloned() {
+        let locator = node.blockchain.lock().await.block_locator();
+        peers.send(&best, Message::RequestBlocks(locator));
+    }
+    for peer in peers.select_outbound(&current_node_address, now) {
+        tokio::spawn(maintain_peer(peer, node.clone()));
+    }
+}
 
-    for peer in peers {
-        if peer != current_node_address && !active_peers.contains(&peer) {
-            if let Err(err) = try_connect_peer(&peer).await {
-                eprintln!("Failed to connect to {}: {}", peer, err);
+// Keeps a session with a peer up, reconnecting with exponential backoff whenever the connection fails or drops.
+// Gives up once the peer failed `MAX_RECONNECTS` times in a row, so `connect_to_peers` can pick another one.
+pub async fn maintain_peer(address: String, node: Node) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match TcpStream::connect(&address).await {
+            Ok(stream) => {
+                println!("Successfully connected to peer: {}", address);
+                backoff = INITIAL_BACKOFF;
+                if let Err(err) =
+                    run_session(stream, address.clone(), node.clone(), Direction::Outbound).await
+                {
+                    eprintln!("Session with {} ended: {}", address, err);
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to connect to {}: {}", address, err);
+                node.peers
+                    .lock()
+                    .await
+                    .address_book_mut()
+                    .mark_failure(&address, Utc::now().timestamp());
             }
         }
+
+        let mut peers = node.peers.lock().await;
+        let failures = peers
+            .address_book()
+            .get(&address)
+            .map(|entry| entry.failures);
+        if failures.is_none_or(|failures| failures >= MAX_RECONNECTS) {
+            peers.outbound_ended(&address);
+            return;
+        }
+        drop(peers);
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
     }
 }
 
-pub async fn try_connect_peer(address: &str) -> Result<(), CustomError> {
-    let mut stream = TcpStream::connect(address).await?;
-
-    // Logging a successful connection
-    println!("Successfully connected to peer: {}", address);
-
-    // Add to the active peers list
-    let mut active_peers = ACTIVE_PEERS.lock().await;
-    active_peers.insert(address.to_string());
+// Mines a block from the pending transactions whenever a new transaction asks for one. The proof of work is searched
+// on a blocking thread, so neither the runtime nor the blockchain is held up meanwhile. The mined block is accepted
+// like one from a peer, which fails harmlessly if the chain moved on in the meantime, and broadcast to all peers.
+pub async fn mine(node: Node) {
+    loop {
+        node.mine.notified().await;
+        let pending = node
+            .blockchain
+            .lock()
+            .await
+            .pending_block(&node.miner_address);
+        let mut block = match pending {
+            Ok(block) => block,
+            Err(err) => {
+                eprintln!("Failed to assemble block: {}", err);
+                continue;
+            }
+        };
+        block = match task::spawn_blocking(move || {
+            block.mine_block();
+            block
+        })
+        .await
+        {
+            Ok(block) => block,
+            Err(err) => {
+                eprintln!("Mining failed: {}", err);
+                continue;
+            }
+        };
 
-    let message = Message::RequestBlockchain;
-    let serialized_message = serde_json::to_string(&message)?;
-    stream.write_all(serialized_message.as_bytes()).await?;
-    Ok(())
+        if let Err(err) = node.blockchain.lock().await.accept_block(block.clone()) {
+            eprintln!("Failed to add block: {}", err);
+            continue;
+        }
+        node.broadcast(&Message::BroadcastBlock(block), None).await;
+    }
 }
 
-pub async fn start_server(
-    port: String,
-    blockchain: Arc<Mutex<Blockchain>>,
-) -> Result<(), CustomError> {
+// Listens on every interface. Which address peers are told to reach us at is up to the node's configuration.
+pub async fn start_server(port: String, node: Node) -> Result<(), CustomError> {
     let address = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(address).await?;
+    serve(listener, node, MAX_INBOUND).await
+}
+
+// Accepts inbound connections, each of which becomes a session just like the ones we open ourselves.
+// Connections beyond `max_inbound` sessions, whether they finished the handshake or not, are closed right away.
+pub async fn serve(
+    listener: TcpListener,
+    node: Node,
+    max_inbound: usize,
+) -> Result<(), CustomError> {
+    let slots = Arc::new(Semaphore::new(max_inbound));
     loop {
-        let (stream, _) = listener.accept().await?;
-        let blockchain_clone = blockchain.clone(); // Clone the Arc
+        let (stream, address) = listener.accept().await?;
+        let slot = match slots.clone().try_acquire_owned() {
+            Ok(slot) => slot,
+            Err(_) => {
+                eprintln!("Refusing {}, too many inbound peers", address);
+                continue;
+            }
+        };
+        let node = node.clone(); // Clone the handles
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, blockchain_clone).await {
-                eprintln!("Error handling connection: {}", e);
+            let address = address.to_string();
+            if let Err(e) = run_session(stream, address.clone(), node, Direction::Inbound).await {
+                eprintln!("Session with {} ended: {}", address, e);
             }
+            drop(slot);
         });
     }
 }
 
-pub async fn write_message(stream: &mut TcpStream, message: &Message) -> Result<(), CustomError> {
-    let serialized_message = serde_json::to_string(&message)?;
-    stream.write_all(serialized_message.as_bytes()).await?;
+// The first page of `blocks` to answer a `RequestBlocks` with: as many as fit in a message, but at least one
+fn first_page(blocks: &[Block]) -> Vec<Block> {
+    let mut size = 0;
+    let mut page = Vec::new();
+    for block in blocks.iter().take(MAX_BLOCKS_PER_MESSAGE) {
+        size += serde_json::to_vec(block).map_or(0, |json| json.len());
+        if !page.is_empty() && size > MAX_PAGE_SIZE {
+            break;
+        }
+        page.push(block.clone());
+    }
+    page
+}
+
+/// What we tell peers about ourselves in the handshake.
+pub fn local_version(blockchain: &Blockchain, listen_address: Option<String>) -> VersionInfo {
+    VersionInfo {
+        protocol_version: PROTOCOL_VERSION,
+        network: blockchain.params().network.clone(),
+        genesis_hash: blockchain.chain[0].hash.clone(),
+        best_height: blockchain.chain.last().unwrap().header.index,
+        services: NODE_NETWORK,
+        user_agent: USER_AGENT.to_string(),
+        listen_address,
+        timestamp: Utc::now().timestamp(),
+    }
+}
+
+// Where an inbound peer listens: the port it claims, at the IP it connected from, so it can't have us
+// vouch for an address that isn't its own
+fn observed_listen_address(observed: &str, claimed: &str) -> Option<String> {
+    let observed: SocketAddr = observed.parse().ok()?;
+    let claimed: SocketAddr = claimed.parse().ok()?;
+    Some(SocketAddr::new(observed.ip(), claimed.port()).to_string())
+}
+
+// Checks that a peer is on our chain and speaks a version of the protocol we understand
+fn check_version(local: &VersionInfo, remote: &VersionInfo) -> Result<(), CustomError> {
+    if remote.genesis_hash != local.genesis_hash {
+        return Err(CustomError::new(
+            "Peer is on a chain with another genesis block",
+        ));
+    }
+    if remote.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(CustomError::new(
+            "Peer speaks an unsupported protocol version",
+        ));
+    }
     Ok(())
 }
 
-// Responsible for managing the communication with another node (peer) in the P2P network once a connection is established.
-// This communication is centered around messages, and the content and type of each message dictate the action taken.
-// Throughout this function, the shared instance of the blockchain is accessed using the Arc and Mutex wrappers to ensure safe concurrent access across multiple threads/tasks.
-pub async fn handle_connection(
+/// Introduces us to a peer and the peer to us, which both sides do the same way: each sends its `Version`,
+/// checks the one it receives and acknowledges it with a `Verack`. Fails if the peer is incompatible.
+/// Until the handshake is over, a peer can't make us read more than `MAX_HANDSHAKE_SIZE` bytes at a time.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    magic: [u8; 4],
+    local: &VersionInfo,
+) -> Result<VersionInfo, CustomError> {
+    codec::write_frame(stream, magic, &Message::Version(local.clone())).await?;
+    let remote = match codec::read_limited_frame(stream, magic, codec::MAX_HANDSHAKE_SIZE).await? {
+        Message::Version(remote) => remote,
+        _ => return Err(CustomError::new("Peer didn't start with a version message")),
+    };
+    check_version(local, &remote)?;
+    codec::write_frame(stream, magic, &Message::Verack).await?;
+    match codec::read_limited_frame(stream, magic, codec::MAX_HANDSHAKE_SIZE).await? {
+        Message::Verack => Ok(remote),
+        _ => Err(CustomError::new("Peer didn't acknowledge our version")),
+    }
+}
+
+// Runs a session over an established connection until either side closes it or it fails. It starts with the
+// handshake, after which messages from the peer are handled one after another as they arrive, while everything
+// queued for the peer is written by a separate loop. The peer manager lists the peer for as long as the session runs.
+pub async fn run_session(
     mut stream: TcpStream,
-    blockchain: Arc<Mutex<Blockchain>>,
+    address: String,
+    node: Node,
+    direction: Direction,
+) -> Result<(), CustomError> {
+    let listen_address = node.peers.lock().await.listen_address().cloned();
+    let (magic, local) = {
+        let blockchain = node.blockchain.lock().await;
+        (
+            blockchain.params().magic,
+            local_version(&blockchain, listen_address),
+        )
+    };
+    let handshake = timeout(
+        Duration::from_secs(HANDSHAKE_TIMEOUT),
+        handshake(&mut stream, magic, &local),
+    )
+    .await
+    .map_err(|_| CustomError::new("Handshake timed out"))
+    .and_then(|result| result);
+
+    // Outbound addresses are rated by whether the handshake works out. Inbound peers tell us where they listen.
+    let now = Utc::now().timestamp();
+    let remote = {
+        let mut peers = node.peers.lock().await;
+        let book = peers.address_book_mut();
+        match (handshake, direction) {
+            (Ok(remote), Direction::Outbound) => {
+                book.mark_success(&address, now);
+                remote
+            }
+            (Ok(remote), Direction::Inbound) => {
+                let listen_address = remote
+                    .listen_address
+                    .as_deref()
+                    .and_then(|claimed| observed_listen_address(&address, claimed));
+                if let Some(listen_address) = listen_address {
+                    book.add(&listen_address, now);
+                }
+                remote
+            }
+            (Err(err), direction) => {
+                if direction == Direction::Outbound {
+                    book.mark_failure(&address, now);
+                }
+                return Err(err);
+            }
+        }
+    };
+    // Both sides speak the older of the two versions
+    let version = remote.protocol_version.min(PROTOCOL_VERSION);
+    println!(
+        "Session with {} ({}, protocol {}, height {})",
+        address, remote.user_agent, version, remote.best_height
+    );
+    // Only the peers we chose ourselves get a say in the network time, so inbound connections can't skew our clock
+    if direction == Direction::Outbound {
+        node.blockchain
+            .lock()
+            .await
+            .add_time_sample(&address, remote.timestamp);
+    }
+
+    let (reader, writer) = stream.into_split();
+    let (sender, outbox) = mpsc::channel(OUTBOUND_QUEUE);
+    {
+        let mut peers = node.peers.lock().await;
+        peers.connect(&address, direction, remote.clone(), sender.clone(), now);
+
+        // Catch up with a peer that is ahead of us, and learn about more peers from the ones we chose
+        if remote.best_height > local.best_height {
+            let locator = node.blockchain.lock().await.block_locator();
+            peers.send(&address, Message::RequestBlocks(locator));
+        }
+        if direction == Direction::Outbound {
+            peers.send(&address, Message::GetAddr);
+        }
+    }
+
+    let result = tokio::select! {
+        result = read_messages(reader, magic, &address, &node) => result,
+        result = write_messages(writer, magic, outbox) => result,
+    };
+
+    let peer = node.peers.lock().await.disconnect(&address, &sender);
+    if peer.is_some_and(|peer| peer.direction == Direction::Outbound) {
+        node.blockchain.lock().await.remove_time_sample(&address);
+    }
+    result
+}
+
+async fn read_messages(
+    mut reader: OwnedReadHalf,
+    magic: [u8; 4],
+    address: &str,
+    node: &Node,
 ) -> Result<(), CustomError> {
-    // Reads incoming data from the connection stream into the buffer. n is the number of bytes read.
-    let mut buffer = [0; 1024];
-    let n = stream.read(&mut buffer).await?;
+    loop {
+        let message = codec::read_frame(&mut reader, magic).await?;
+        handle_message(message, address, node).await;
+    }
+}
 
-    // // Gets the address of the remote peer (i.e., the node you're communicating with) and adds it to the list of known peers.
-    // let remote_addr = stream.peer_addr().unwrap().to_string();
-    // add_peer(remote_addr).await;
+async fn write_messages(
+    mut writer: OwnedWriteHalf,
+    magic: [u8; 4],
+    mut outbox: mpsc::Receiver<Message>,
+) -> Result<(), CustomError> {
+    while let Some(message) = outbox.recv().await {
+        codec::write_frame(&mut writer, magic, &message).await?;
+    }
+    Ok(())
+}
 
-    // Converts the incoming bytes into a Message type using serde_json for deserialization.
-    let message: Message = serde_json::from_slice(&buffer[0..n])?;
+// Responsible for handling one message from another node (peer) in the P2P network. The content and type of each
+// message dictate the action taken; replies go into the queue of the peer's session through the peer manager.
+// Throughout this function, the shared instance of the blockchain is accessed using the Arc and Mutex wrappers to ensure safe concurrent access across multiple threads/tasks.
+async fn handle_message(message: Message, address: &str, node: &Node) {
+    let blockchain = &node.blockchain;
+    // Blocks the peer sends tell us how far its chain goes
+    let height = match &message {
+        Message::BroadcastBlock(block) | Message::SendBlock(block) => Some(block.header.index),
+        Message::SendBlocks(blocks) => blocks.last().map(|block| block.header.index),
+        _ => None,
+    };
+    node.peers
+        .lock()
+        .await
+        .record_message(address, height, Utc::now().timestamp());
+    let reply = |message: Message| async move { node.peers.lock().await.send(address, message) };
 
     match message {
-        // If the incoming message is a request for the blockchain, this part sends back the entire blockchain to the requester.
-        Message::RequestBlockchain => {
-            let blockchain_data = blockchain.lock().await;
-            let serialized_blockchain = serde_json::to_string(&*blockchain_data)?;
-            stream.write_all(serialized_blockchain.as_bytes()).await?;
+        // The handshake is over by the time messages get here
+        Message::Version(_) | Message::Verack => {
+            eprintln!("Ignoring a repeated handshake from {}", address);
         }
 
-        // If another peer sends its blockchain, this part checks if the received blockchain is valid and longer than the current blockchain.
-        // If both conditions are met, it replaces the current blockchain with the received one.
-        Message::SendBlockchain(blocks) => {
+        // A peer asks which nodes we know of
+        Message::GetAddr => {
+            let peers = node.peers.lock().await;
+            let addresses = peers
+                .address_book()
+                .addresses_to_share(Utc::now().timestamp());
+            peers.send(address, Message::Addr(addresses));
+        }
+
+        // Nodes a peer knows of, which we may connect to later. Times in the future are taken as now, and
+        // addresses local to the peer's machine mean nothing to us.
+        Message::Addr(addresses) => {
+            let now = Utc::now().timestamp();
+            let mut peers = node.peers.lock().await;
+            for (peer, last_seen) in addresses.into_iter().take(MAX_ADDR_PER_MESSAGE) {
+                if address_book::is_shareable(&peer) {
+                    peers.address_book_mut().add(&peer, last_seen.min(now));
+                }
+            }
+        }
+
+        // A peer catching up asks for the blocks of our chain after where its chain and ours part.
+        // They are sent a page at a time, and the peer asks for the next page once it has the previous one.
+        Message::RequestBlocks(locator) => {
+            let page = first_page(blockchain.lock().await.blocks_after(&locator));
+            reply(Message::SendBlocks(page)).await;
+        }
+
+        // A page of a peer's chain is added to the block tree. If the blocks form a valid branch with more
+        // accumulated proof of work than the current chain, the chain reorganizes onto it. While the peer has
+        // more blocks, we ask for the page after the last block we got.
+        Message::SendBlocks(blocks) => {
+            let last = blocks.last().map(|block| block.hash.clone());
             let mut blockchain_data = blockchain.lock().await;
-            let received_blockchain = Blockchain::from(&blockchain_data, blocks);
-            if received_blockchain.is_chain_valid()
-                && received_blockchain.chain.len() > blockchain_data.chain.len()
-            {
-                blockchain_data.chain = received_blockchain.chain;
-                println!("Blockchain replaced with a longer valid chain from a peer.");
+            if blockchain_data.replace_chain(blocks) {
+                println!("Switched to a heavier valid chain from a peer.");
+            }
+            let next = last
+                .filter(|last| blockchain_data.block(last).is_some())
+                .map(|last| {
+                    let mut locator = vec![last];
+                    locator.extend(blockchain_data.block_locator());
+                    locator
+                });
+            drop(blockchain_data);
+            let peers = node.peers.lock().await;
+            let behind = height
+                .zip(peers.peer(address))
+                .is_some_and(|(height, peer)| peer.best_height > height);
+            if let Some(locator) = next.filter(|_| behind) {
+                peers.send(address, Message::RequestBlocks(locator));
             }
         }
 
-        // Upon receiving a new transaction, the transaction is added to a new block (this may not be the best approach in a real-world scenario, but it works for the sake of this example).
-        // After adding, it broadcasts this transaction to all known peers.
+        // Upon receiving a new transaction, the transaction is added to the transaction pool and the mining task is
+        // asked to mine a block from the pending transactions with the best fee rates (this may not be the best
+        // approach in a real-world scenario, but it works for the sake of this example). After adding, it broadcasts
+        // this transaction to all known peers.
         Message::NewTransaction(transaction) => {
-            let mut blockchain_data = blockchain.lock().await;
-            blockchain_data.add_block(vec![transaction.clone()]);
-
-            for peer_address in &blockchain_data.peers {
-                if let Ok(mut peer_stream) = TcpStream::connect(peer_address).await {
-                    write_message(
-                        &mut peer_stream,
-                        &Message::BroadcastTransaction(transaction.clone()),
-                    )
-                    .await?;
-                }
+            if let Err(err) = blockchain.lock().await.add_transaction(transaction.clone()) {
+                eprintln!("Rejected transaction: {}", err);
+                return;
             }
+            node.mine.notify_one();
+            node.broadcast(&Message::BroadcastTransaction(transaction), None)
+                .await;
         }
 
         // If a transaction is being broadcasted from another peer, this code validates the transaction.
         // If valid, it's added to the transaction pool and then re-broadcasted to all other known peers.
         Message::BroadcastTransaction(transaction) => {
-            let mut blockchain_data = blockchain.lock().await;
-
-            // 1. Validate the transaction
-            if blockchain_data.validate_transaction(&transaction) {
-                // 2. Add the transaction to the transaction pool
-                blockchain_data
-                    .pending_transactions
-                    .push(transaction.clone());
-
-                // 3. Broadcast the transaction to all other known peers
-                for peer_address in &blockchain_data.peers {
-                    if peer_address != &stream.peer_addr().unwrap().to_string() {
-                        // Avoid sending back to the sender
-                        if let Ok(mut peer_stream) = TcpStream::connect(peer_address).await {
-                            write_message(
-                                &mut peer_stream,
-                                &Message::BroadcastTransaction(transaction.clone()),
-                            )
-                            .await?;
-                        }
-                    }
-                }
+            // 1. Validate the transaction and 2. add it to the transaction pool
+            let added = blockchain
+                .lock()
+                .await
+                .add_transaction(transaction.clone())
+                .is_ok();
+            if added {
+                // 3. Broadcast the transaction to all other known peers, avoiding sending back to the sender
+                node.broadcast(&Message::BroadcastTransaction(transaction), Some(address))
+                    .await;
             }
         }
 
-        // When a block is broadcasted from another peer, this code attempts to add the block's transactions to the blockchain.
+        // When a block is broadcasted from another peer, this code validates it and adds it to the block tree as it is.
         // If successful, the block is then broadcasted to all other peers.
         Message::BroadcastBlock(block) => {
             let mut blockchain_data = blockchain.lock().await;
-            // Extract transactions from the block
-            let transactions = block.transactions.clone();
+            // A block building on one we don't have waits in the orphan pool while we ask the sender for its parent
+            if blockchain_data.block(&block.header.previous_hash).is_none() {
+                match blockchain_data.add_orphan(block, address) {
+                    Ok(missing) => {
+                        drop(blockchain_data);
+                        reply(Message::RequestBlock(missing)).await;
+                    }
+                    Err(err) => eprintln!("Rejected orphan block: {}", err),
+                }
+                return;
+            }
 
-            match blockchain_data.add_block(transactions) {
+            // The block is validated and added as it is, so every node ends up with the same chain
+            match blockchain_data.accept_block(block.clone()) {
                 Ok(_) => {
+                    drop(blockchain_data);
                     // Broadcast the block to all other known peers
-                    for peer_address in &blockchain_data.peers {
-                        if peer_address != &stream.peer_addr().unwrap().to_string() {
-                            if let Ok(mut peer_stream) = TcpStream::connect(peer_address).await {
-                                write_message(
-                                    &mut peer_stream,
-                                    &Message::BroadcastBlock(block.clone()),
-                                )
-                                .await?;
-                            }
-                        }
-                    }
+                    node.broadcast(&Message::BroadcastBlock(block), Some(address))
+                        .await;
                 }
                 Err(err) => {
                     eprintln!("Failed to add block: {}", err);
                 }
             }
         }
+
+        // A peer asks for a block we know of, e.g. because it is missing the parent of an orphan
+        Message::RequestBlock(hash) => {
+            let block = blockchain
+                .lock()
+                .await
+                .block(&hash)
+                .map(|entry| entry.block.clone());
+            if let Some(block) = block {
+                reply(Message::SendBlock(block)).await;
+            }
+        }
+
+        // A block we asked for. It is added to the block tree along with any orphans waiting for it,
+        // unless it is an orphan itself, in which case we keep asking for its ancestors.
+        Message::SendBlock(block) => {
+            let mut blockchain_data = blockchain.lock().await;
+            if blockchain_data.block(&block.header.previous_hash).is_none() {
+                match blockchain_data.add_orphan(block, address) {
+                    Ok(missing) => {
+                        drop(blockchain_data);
+                        reply(Message::RequestBlock(missing)).await;
+                    }
+                    Err(err) => eprintln!("Rejected orphan block: {}", err),
+                }
+            } else if let Err(err) = blockchain_data.accept_block(block) {
+                eprintln!("Failed to add block: {}", err);
+            }
+        }
+
+        // A peer asks for proof that a transaction is confirmed. The reply holds just the block header and the
+        // Merkle branch, so the peer can check it without downloading the block.
+        Message::RequestTransactionProof(txid) => {
+            let proof = blockchain.lock().await.transaction_proof(&txid);
+            reply(Message::SendTransactionProof(txid, proof)).await;
+        }
+
+        // Checks a proof received from a peer against the header it came with, which has to be on our chain
+        Message::SendTransactionProof(txid, proof) => match proof {
+            Some((header, proof))
+                if blockchain
+                    .lock()
+                    .await
+                    .verify_transaction_proof(&txid, &header, &proof) =>
+            {
+                println!(
+                    "Transaction {} is confirmed in block {}",
+                    txid, header.index
+                );
+            }
+            Some(_) => eprintln!("Received an invalid proof for transaction {}", txid),
+            None => println!("Peer has no confirmed transaction {}", txid),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{params::ChainParams, utxo::LedgerMode};
+    use secp256k1::SecretKey;
+
+    #[tokio::test]
+    async fn test_session() {
+        let miner = Address::from_secret_key(&SecretKey::from_slice(&[9; 32]).unwrap());
+        let params = ChainParams::regtest();
+        let mut blockchain = Blockchain::new(params.clone(), LedgerMode::Account);
+        let mut block = blockchain.pending_block(&miner).unwrap();
+        block.mine_block();
+        blockchain.accept_block(block).unwrap();
+        let tip = blockchain.chain[1].clone();
+        let node = Node::new(blockchain, PeerManager::new(), miner);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, node.clone(), MAX_INBOUND));
+
+        // The node introduces itself, and is ahead of a client that only has the genesis block
+        let client = Blockchain::new(params.clone(), LedgerMode::Account);
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let address = stream.local_addr().unwrap().to_string();
+        let mut version = local_version(&client, Some(String::from("10.8.8.8:8000")));
+        let remote = handshake(&mut stream, params.magic, &version)
+            .await
+            .unwrap();
+        assert_eq!(remote.genesis_hash, client.chain[0].hash);
+        assert_eq!(remote.best_height, 1);
+
+        // Any number of messages go over the same connection
+        for _ in 0..2 {
+            let request = Message::RequestBlock(tip.hash.clone());
+            codec::write_frame(&mut stream, params.magic, &request)
+                .await
+                .unwrap();
+            match codec::read_frame(&mut stream, params.magic).await.unwrap() {
+                Message::SendBlock(block) => assert_eq!(block.hash, tip.hash),
+                _ => panic!("Expected a block"),
+            }
+        }
+
+        // The client catches up with the blocks after the ones it has
+        let request = Message::RequestBlocks(client.block_locator());
+        codec::write_frame(&mut stream, params.magic, &request)
+            .await
+            .unwrap();
+        match codec::read_frame(&mut stream, params.magic).await.unwrap() {
+            Message::SendBlocks(blocks) => {
+                assert_eq!(blocks.len(), 1);
+                assert_eq!(blocks[0].hash, tip.hash);
+            }
+            _ => panic!("Expected blocks"),
+        }
+        let direction = node.peers.lock().await.peer(&address).unwrap().direction;
+        assert_eq!(direction, Direction::Inbound);
+
+        // Broadcasts from the node reach the peer over its session
+        node.broadcast(&Message::BroadcastBlock(tip.clone()), None)
+            .await;
+        match codec::read_frame(&mut stream, params.magic).await.unwrap() {
+            Message::BroadcastBlock(block) => assert_eq!(block.hash, tip.hash),
+            _ => panic!("Expected a block"),
+        }
+
+        // Peers exchange the addresses they know of, including where inbound peers listen. That is the port the
+        // peer claims, but at the IP it connected from.
+        let now = Utc::now().timestamp();
+        let addr = Message::Addr(vec![
+            (String::from("10.9.9.9:8000"), now + 1000),
+            (String::from("127.0.0.1:9000"), now),
+        ]);
+        codec::write_frame(&mut stream, params.magic, &addr)
+            .await
+            .unwrap();
+        codec::write_frame(&mut stream, params.magic, &Message::GetAddr)
+            .await
+            .unwrap();
+        match codec::read_frame(&mut stream, params.magic).await.unwrap() {
+            Message::Addr(addresses) => {
+                assert_eq!(addresses.len(), 1);
+                assert!(addresses
+                    .iter()
+                    .any(|(peer, seen)| peer == "10.9.9.9:8000" && *seen < now + 1000));
+            }
+            _ => panic!("Expected addresses"),
+        }
+        // Loopback addresses are known locally but never gossiped, nor taken from peers
+        let peers = node.peers.lock().await;
+        assert!(peers.address_book().get("127.0.0.1:8000").is_some());
+        assert!(peers.address_book().get("10.8.8.8:8000").is_none());
+        assert!(peers.address_book().get("127.0.0.1:9000").is_none());
+        drop(peers);
+
+        // Closing the connection ends the session
+        drop(stream);
+        for _ in 0..100 {
+            if node.peers.lock().await.peer(&address).is_none() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(node.peers.lock().await.peer(&address).is_none());
+
+        // A peer on another chain is disconnected after the node introduced itself
+        let mut refused = Vec::new();
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        refused.push(stream.local_addr().unwrap().to_string());
+        let other_chain = Blockchain::new(ChainParams::mainnet(), LedgerMode::Account);
+        version = local_version(&other_chain, None);
+        assert!(handshake(&mut stream, params.magic, &version)
+            .await
+            .is_err());
+        assert!(codec::read_frame(&mut stream, params.magic).await.is_err());
+
+        // And so is one that speaks a protocol we no longer support
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        refused.push(stream.local_addr().unwrap().to_string());
+        version = local_version(&client, None);
+        version.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        codec::write_frame(&mut stream, params.magic, &Message::Version(version))
+            .await
+            .unwrap();
+        assert!(matches!(
+            codec::read_frame(&mut stream, params.magic).await.unwrap(),
+            Message::Version(_)
+        ));
+        assert!(codec::read_frame(&mut stream, params.magic).await.is_err());
+
+        // Nor may a peer send anything large before the handshake is over
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        refused.push(stream.local_addr().unwrap().to_string());
+        version = local_version(&client, None);
+        version.user_agent = "x".repeat(codec::MAX_HANDSHAKE_SIZE);
+        assert!(handshake(&mut stream, params.magic, &version)
+            .await
+            .is_err());
+        let peers = node.peers.lock().await;
+        assert!(refused.iter().all(|address| peers.peer(address).is_none()));
+        drop(peers);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_inbound_limit() {
+        let miner = Address::from_secret_key(&SecretKey::from_slice(&[9; 32]).unwrap());
+        let params = ChainParams::regtest();
+        let node = Node::new(
+            Blockchain::new(params.clone(), LedgerMode::Account),
+            PeerManager::new(),
+            miner,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, node.clone(), 1));
+
+        // The first peer takes the only slot, even before its handshake, and the next one is turned away
+        let client = Blockchain::new(params.clone(), LedgerMode::Account);
+        let mut first = TcpStream::connect(server_address).await.unwrap();
+        let mut second = TcpStream::connect(server_address).await.unwrap();
+        let version = local_version(&client, None);
+        assert!(handshake(&mut second, params.magic, &version)
+            .await
+            .is_err());
+        assert!(handshake(&mut first, params.magic, &version).await.is_ok());
+
+        // Once the first peer leaves, its slot is free again
+        drop(first);
+        let mut third = None;
+        for _ in 0..100 {
+            let mut stream = TcpStream::connect(server_address).await.unwrap();
+            if handshake(&mut stream, params.magic, &version).await.is_ok() {
+                third = Some(stream);
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(third.is_some());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_mining_task() {
+        let miner = Address::from_secret_key(&SecretKey::from_slice(&[9; 32]).unwrap());
+        let node = Node::new(
+            Blockchain::new(ChainParams::regtest(), LedgerMode::Account),
+            PeerManager::new(),
+            miner,
+        );
+        let mining = tokio::spawn(mine(node.clone()));
+
+        // Nothing is mined until a block is asked for, and then exactly one
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(node.blockchain.lock().await.chain.len(), 1);
+        node.mine.notify_one();
+        for _ in 0..100 {
+            if node.blockchain.lock().await.chain.len() > 1 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        let blockchain = node.blockchain.lock().await;
+        assert_eq!(blockchain.chain.len(), 2);
+        assert_eq!(blockchain.chain[1].transactions[0].receiver, miner);
+        mining.abort();
     }
-    Ok(())
 }
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use crate::block::Block;
+
+// Most orphan blocks held at once, so peers can't fill up our memory with blocks that never connect
+pub const MAX_ORPHANS: usize = 100;
+// Most orphan blocks held for a single peer, so no peer can push out the orphans others sent
+pub const MAX_ORPHANS_PER_PEER: usize = 10;
+// Seconds after which an orphan whose parent never showed up is dropped
+pub const ORPHAN_EXPIRY: i64 = 20 * 60;
+
+#[derive(Clone, Debug)]
+struct Orphan {
+    block: Block,
+    peer: String,  // Who sent the block
+    received: i64, // Unix timestamp of when the block arrived
+}
+
+// Blocks that arrived before their parent, indexed by hash, waiting for the parent to be added to the block tree
+#[derive(Clone, Debug, Default)]
+pub struct OrphanPool {
+    orphans: HashMap<String, Orphan>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool::default()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Adds a block whose parent is unknown, sent by `peer` at time `now`. Expired orphans are dropped first.
+    /// A peer at its limit makes room with its own oldest orphan. Otherwise, if the pool is full, the oldest orphan
+    /// of the peers holding the most makes room.
+    pub fn insert(&mut self, block: Block, peer: &str, now: i64) {
+        self.expire(now);
+        if self.contains(&block.hash) {
+            return;
+        }
+        let mut held: HashMap<&str, usize> = HashMap::new();
+        for orphan in self.orphans.values() {
+            *held.entry(&orphan.peer).or_default() += 1;
+        }
+        let most = held.values().copied().max().unwrap_or(0);
+        let at_limit = held.get(peer).copied().unwrap_or(0) >= MAX_ORPHANS_PER_PEER;
+        let full = self.orphans.len() >= MAX_ORPHANS;
+        let oldest = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| match at_limit {
+                true => orphan.peer == peer,
+                false => full && held[orphan.peer.as_str()] == most,
+            })
+            .min_by_key(|(_, orphan)| orphan.received)
+            .map(|(hash, _)| hash.clone());
+        if let Some(oldest) = oldest {
+            self.orphans.remove(&oldest);
+        }
+        let hash = block.hash.clone();
+        self.orphans.insert(
+            hash,
+            Orphan {
+                block,
+                peer: peer.to_string(),
+                received: now,
+            },
+        );
+    }
+
+    /// Drops every orphan that has waited longer than `ORPHAN_EXPIRY` by time `now`.
+    pub fn expire(&mut self, now: i64) {
+        self.orphans
+            .retain(|_, orphan| now - orphan.received <= ORPHAN_EXPIRY);
+    }
+
+    /// Removes and returns the orphans building on the block `parent`.
+    pub fn take_children(&mut self, parent: &str) -> Vec<Block> {
+        let children: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.block.header.previous_hash == parent)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        children
+            .into_iter()
+            .filter_map(|hash| self.orphans.remove(&hash))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Hash of the block that has to arrive before the orphan `hash` can connect:
+    /// the parent of its earliest ancestor that is an orphan too.
+    pub fn missing_ancestor(&self, hash: &str) -> Option<String> {
+        let mut orphan = self.orphans.get(hash)?;
+        // Bounded by the pool size, in case the orphans somehow form a cycle
+        for _ in 0..self.orphans.len() {
+            match self.orphans.get(&orphan.block.header.previous_hash) {
+                Some(parent) => orphan = parent,
+                None => break,
+            }
+        }
+        Some(orphan.block.header.previous_hash.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(parent: &Block, timestamp: i64) -> Block {
+        Block::new(
+            parent.header.index + 1,
+            timestamp,
+            0,
+            parent.hash.clone(),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_orphan_pool() {
+        let genesis = Block::new(0, 0, 0, String::from("0"), vec![]);
+        let first = child(&genesis, 1);
+        let second = child(&first, 2);
+        let sibling = child(&first, 3);
+
+        let mut pool = OrphanPool::new();
+        pool.insert(second.clone(), "peer", 0);
+        pool.insert(sibling.clone(), "peer", 0);
+        // Both wait for the first block, which is what has to be requested
+        assert_eq!(
+            pool.missing_ancestor(&second.hash),
+            Some(first.hash.clone())
+        );
+        pool.insert(first.clone(), "peer", 0);
+        assert_eq!(
+            pool.missing_ancestor(&second.hash),
+            Some(genesis.hash.clone())
+        );
+        assert_eq!(pool.missing_ancestor(&genesis.hash), None);
+
+        let children = pool.take_children(&genesis.hash);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].hash, first.hash);
+        assert_eq!(pool.take_children(&first.hash).len(), 2);
+        assert!(pool.orphans.is_empty());
+    }
+
+    #[test]
+    fn test_orphan_pool_is_bounded() {
+        let genesis = Block::new(0, 0, 0, String::from("0"), vec![]);
+        let peer = |timestamp: i64| format!("10.0.0.{}:8000", timestamp / 10);
+        let mut pool = OrphanPool::new();
+        for timestamp in 0..MAX_ORPHANS as i64 {
+            pool.insert(child(&genesis, timestamp), &peer(timestamp), timestamp);
+        }
+        assert_eq!(pool.orphans.len(), MAX_ORPHANS);
+
+        // A peer at its limit only pushes out its own orphans
+        let newest = child(&genesis, -1);
+        pool.insert(newest.clone(), &peer(50), MAX_ORPHANS as i64);
+        assert_eq!(pool.orphans.len(), MAX_ORPHANS);
+        assert!(pool.contains(&newest.hash));
+        assert!(!pool.contains(&child(&genesis, 50).hash));
+
+        // A new peer in a full pool pushes out the oldest orphan of the peers holding the most
+        pool.insert(child(&genesis, -2), &peer(50), MAX_ORPHANS as i64);
+        pool.insert(child(&genesis, -3), "10.1.0.1:8000", MAX_ORPHANS as i64);
+        assert_eq!(pool.orphans.len(), MAX_ORPHANS);
+        assert!(pool.contains(&child(&genesis, -3).hash));
+        assert!(!pool.contains(&child(&genesis, 0).hash));
+        assert!(!pool.contains(&child(&genesis, 51).hash));
+
+        // Orphans expire once they've waited long enough, which leaves those received from time 50 on
+        pool.expire(ORPHAN_EXPIRY + 50);
+        assert_eq!(pool.orphans.len(), 51);
+        pool.expire(ORPHAN_EXPIRY + MAX_ORPHANS as i64 + 1);
+        assert!(pool.orphans.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount::{Amount, MAX_SUPPLY, UNITS_PER_COIN},
+    block::Block,
+    custom_error::CustomError,
+    difficulty,
+    transaction::Transaction,
+    utxo::TxOutput,
+};
+use primitive_types::U256;
+
+// The mainnet values of the consensus parameters, which everything else reads from `ChainParams`
+const TARGET_BLOCK_TIME: i64 = 10; // Seconds we aim to spend mining each block
+const RETARGET_INTERVAL: u32 = 10; // Blocks after which the difficulty is adjusted
+
+// Target of the genesis block and of every block until the first retarget, about 65 thousand hashes per block.
+// Also the target a new block starts out with, until it is mined for a particular network.
+pub const INITIAL_BITS: u32 = 0x1f00ffff;
+const POW_LIMIT_BITS: u32 = 0x2000ffff; // The easiest target a block may ever have
+const INITIAL_SUBSIDY: Amount = Amount::from_units(50 * UNITS_PER_COIN); // Paid until the first halving
+const HALVING_INTERVAL: u32 = 210_000; // Blocks after which the subsidy is cut in half
+const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60; // Seconds a block may be ahead of the network-adjusted time
+
+// Everything that makes one network different from another. Nodes only talk to each other, and only agree on a chain,
+// if they run with the same parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+    pub network: String,
+    pub magic: [u8; 4], // Starts every message on the wire, so nodes of different networks can't be mixed up
+    pub genesis_timestamp: i64,
+    pub genesis_allocations: Vec<TxOutput>, // Coins that exist from the genesis block on
+    pub target_block_time: i64,             // Seconds
+    pub retarget_interval: u32, // Blocks between difficulty adjustments, 0 to never adjust
+    pub initial_bits: u32,      // Target of the genesis block, in compact form
+    pub pow_limit_bits: u32,    // Easiest target a block may have, in compact form
+    pub initial_subsidy: Amount,
+    pub halving_interval: u32,
+    pub max_future_drift: i64, // Seconds a block timestamp may be ahead of the network-adjusted time
+    pub seed_nodes: Vec<String>,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: String::from("mainnet"),
+            magic: *b"RBCm",
+            genesis_timestamp: 0,
+            genesis_allocations: vec![],
+            target_block_time: TARGET_BLOCK_TIME,
+            retarget_interval: RETARGET_INTERVAL,
+            initial_bits: INITIAL_BITS,
+            pow_limit_bits: POW_LIMIT_BITS,
+            initial_subsidy: INITIAL_SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+            max_future_drift: MAX_FUTURE_DRIFT,
+            seed_nodes: vec![
+                String::from("127.0.0.1:8000"),
+                String::from("127.0.0.1:8001"),
+            ],
+        }
+    }
+
+    // Like mainnet, but with its own genesis block and seed nodes, for trying things out with worthless coins
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: String::from("testnet"),
+            magic: *b"RBCt",
+            genesis_timestamp: 1_700_000_000,
+            seed_nodes: vec![
+                String::from("127.0.0.1:18000"),
+                String::from("127.0.0.1:18001"),
+            ],
+            ..ChainParams::mainnet()
+        }
+    }
+
+    // A local network for tests: blocks take next to no work and the difficulty never changes
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: String::from("regtest"),
+            magic: *b"RBCr",
+            retarget_interval: 0,
+            initial_bits: 0x207fffff,
+            pow_limit_bits: 0x207fffff,
+            halving_interval: 150,
+            seed_nodes: vec![],
+            ..ChainParams::mainnet()
+        }
+    }
+
+    /// One of the presets by network name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(ChainParams::mainnet()),
+            "testnet" => Some(ChainParams::testnet()),
+            "regtest" => Some(ChainParams::regtest()),
+            _ => None,
+        }
+    }
+
+    /// Loads the parameters of a private network from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CustomError> {
+        ChainParams::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CustomError> {
+        let params: ChainParams = serde_json::from_str(json)?;
+        params.validate()?;
+        Ok(params)
+    }
+
+    /// Checks that the parameters describe a network that can actually run.
+    pub fn validate(&self) -> Result<(), CustomError> {
+        let initial = difficulty::target_from_bits(self.initial_bits)
+            .ok_or_else(|| CustomError::new("Invalid initial difficulty"))?;
+        let limit = difficulty::target_from_bits(self.pow_limit_bits)
+            .ok_or_else(|| CustomError::new("Invalid proof of work limit"))?;
+        if initial > limit {
+            return Err(CustomError::new(
+                "Initial difficulty is easier than the proof of work limit",
+            ));
+        }
+        if self.target_block_time <= 0 {
+            return Err(CustomError::new("Invalid target block time"));
+        }
+        // A retarget looks at the time between the first and the last block of an interval, which must be long
+        // enough for the adjustment to be clamped to `MAX_ADJUSTMENT` both ways
+        let retarget_span =
+            (self.retarget_interval as i64 - 1).saturating_mul(self.target_block_time);
+        if self.retarget_interval != 0 && retarget_span < difficulty::MAX_ADJUSTMENT {
+            return Err(CustomError::new("Invalid retarget interval"));
+        }
+        if self.halving_interval == 0 {
+            return Err(CustomError::new("Invalid halving interval"));
+        }
+        if self.max_future_drift < 0 {
+            return Err(CustomError::new("Invalid maximum future drift"));
+        }
+        if !self.initial_subsidy.is_valid() {
+            return Err(CustomError::new("Invalid initial subsidy"));
+        }
+        let allocated =
+            Amount::checked_sum(self.genesis_allocations.iter().map(|output| output.amount));
+        if allocated.is_none_or(|allocated| allocated > MAX_SUPPLY) {
+            return Err(CustomError::new(
+                "Genesis allocations exceed the maximum supply",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The easiest target a block may have. Parameters with an invalid limit allow no block at all.
+    pub fn pow_limit(&self) -> U256 {
+        difficulty::target_from_bits(self.pow_limit_bits).unwrap_or_else(U256::zero)
+    }
+
+    /// The first block of the network, paying out the genesis allocations.
+    pub fn genesis_block(&self) -> Block {
+        // Allocations are paid like coinbases, numbered by their position so that each has its own id
+        let transactions = self
+            .genesis_allocations
+            .iter()
+            .enumerate()
+            .map(|(position, output)| {
+                Transaction::coinbase(output.address, output.amount, position as u32)
+            })
+            .collect();
+        let mut block = Block::new(
+            0,
+            self.genesis_timestamp,
+            0,
+            String::from("0"),
+            transactions,
+        );
+        block.header.bits = self.initial_bits;
+        block.hash = block.calculate_hash();
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use secp256k1::SecretKey;
+
+    #[test]
+    fn test_presets() {
+        for name in ["mainnet", "testnet", "regtest"] {
+            let params = ChainParams::from_name(name).unwrap();
+            assert_eq!(params.network, name);
+            assert!(params.validate().is_ok());
+        }
+        assert!(ChainParams::from_name("moonnet").is_none());
+
+        // Every network has its own genesis block
+        assert_ne!(
+            ChainParams::mainnet().genesis_block().hash,
+            ChainParams::testnet().genesis_block().hash
+        );
+        assert_ne!(
+            ChainParams::mainnet().genesis_block().hash,
+            ChainParams::regtest().genesis_block().hash
+        );
+    }
+
+    #[test]
+    fn test_private_network_from_json() {
+        let alice = Address::from_secret_key(&SecretKey::from_slice(&[1; 32]).unwrap());
+        let mut params = ChainParams::regtest();
+        params.network = String::from("private");
+        params.genesis_allocations = vec![TxOutput {
+            address: alice,
+            amount: Amount::from_coins(1_000).unwrap(),
+        }];
+
+        let json = serde_json::to_string(&params).unwrap();
+        let loaded = ChainParams::from_json(&json).unwrap();
+        assert_eq!(loaded, params);
+        let genesis = loaded.genesis_block();
+        assert_eq!(genesis.transactions.len(), 1);
+        assert_eq!(genesis.transactions[0].receiver, alice);
+
+        // Parameters that can't work are rejected
+        params.genesis_allocations[0].amount = Amount::from_units(MAX_SUPPLY.units() + 1);
+        assert!(ChainParams::from_json(&serde_json::to_string(&params).unwrap()).is_err());
+        params.genesis_allocations.clear();
+        params.initial_bits = 0x2100ffff;
+        assert!(ChainParams::from_json(&serde_json::to_string(&params).unwrap()).is_err());
+        assert!(ChainParams::from_json("{}").is_err());
+
+        // Each problem is reported as what it is
+        let error = |params: &ChainParams| params.validate().unwrap_err().to_string();
+        let mut params = ChainParams::regtest();
+        params.initial_subsidy = Amount::from_units(MAX_SUPPLY.units() + 1);
+        assert_eq!(error(&params), "Invalid initial subsidy");
+        let mut params = ChainParams::regtest();
+        params.pow_limit_bits = 0x2200ffff; // Past 256 bits
+        assert_eq!(error(&params), "Invalid proof of work limit");
+        assert!(params.pow_limit().is_zero());
+        let mut params = ChainParams::regtest();
+        params.target_block_time = 0;
+        assert_eq!(error(&params), "Invalid target block time");
+        let mut params = ChainParams::mainnet();
+        params.retarget_interval = 1; // Nothing to measure between the first and the last block
+        assert_eq!(error(&params), "Invalid retarget interval");
+        params.retarget_interval = 2;
+        params.target_block_time = 1; // Too short for the lower clamp, which would be zero
+        assert_eq!(error(&params), "Invalid retarget interval");
+        params.target_block_time = 4;
+        assert!(params.validate().is_ok());
+        let mut params = ChainParams::regtest();
+        params.halving_interval = 0;
+        assert_eq!(error(&params), "Invalid halving interval");
+        let mut params = ChainParams::regtest();
+        params.max_future_drift = -1;
+        assert_eq!(error(&params), "Invalid maximum future drift");
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    address_book::AddressBook,
+    custom_error::CustomError,
+    messages::{Message, VersionInfo, NODE_NETWORK},
+};
+
+// Number of peers we open sessions with ourselves
+pub const MAX_OUTBOUND: usize = 8;
+// Number of peers that may connect to us at the same time
+pub const MAX_INBOUND: usize = 117;
+
+// Queue of the messages waiting to be written to a peer
+pub type Outbox = mpsc::Sender<Message>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,  // The peer connected to us
+    Outbound, // We connected to the peer, at an address from the address book
+}
+
+// A peer we currently have a session with
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub direction: Direction,
+    pub version: VersionInfo, // What the peer told us about itself in the handshake
+    pub best_height: u32,     // Height of the best block the peer is known to have
+    pub last_message: i64,    // When we last heard from the peer
+    outbox: Outbox,
+}
+
+// Everything the node knows about other nodes: the address book of every node we heard of, the peers we are
+// connected to and the addresses we keep outbound sessions with. Owned by the node and shared by its sessions.
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    address_book: AddressBook,
+    connected: HashMap<String, Peer>,
+    outbound: HashSet<String>, // Addresses with a task keeping a session up, whether it is connected right now or not
+    listen_address: Option<String>, // Where other nodes can reach us, once we listen
+}
+
+impl PeerManager {
+    pub fn new() -> Self {
+        PeerManager::default()
+    }
+
+    /// Starts with the address book saved at `path`, where it is saved from then on.
+    pub fn load(path: &str) -> Result<Self, CustomError> {
+        Ok(PeerManager {
+            address_book: AddressBook::load(path)?,
+            ..PeerManager::default()
+        })
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    pub fn address_book_mut(&mut self) -> &mut AddressBook {
+        &mut self.address_book
+    }
+
+    pub fn listen_address(&self) -> Option<&String> {
+        self.listen_address.as_ref()
+    }
+
+    pub fn set_listen_address(&mut self, address: String) {
+        self.listen_address = Some(address);
+    }
+
+    /// Records a session that completed its handshake. A newer session with the same address replaces an older one.
+    pub fn connect(
+        &mut self,
+        address: &str,
+        direction: Direction,
+        version: VersionInfo,
+        outbox: Outbox,
+        now: i64,
+    ) {
+        let peer = Peer {
+            direction,
+            best_height: version.best_height,
+            version,
+            last_message: now,
+            outbox,
+        };
+        self.connected.insert(address.to_string(), peer);
+    }
+
+    /// Forgets the session writing to `outbox`, unless a newer session with the same address replaced it already.
+    /// Returns the peer of the session if it was forgotten.
+    pub fn disconnect(&mut self, address: &str, outbox: &Outbox) -> Option<Peer> {
+        if self
+            .connected
+            .get(address)
+            .is_some_and(|peer| peer.outbox.same_channel(outbox))
+        {
+            return self.connected.remove(address);
+        }
+        None
+    }
+
+    pub fn peer(&self, address: &str) -> Option<&Peer> {
+        self.connected.get(address)
+    }
+
+    /// Records that a peer sent us a message, possibly announcing a block at `height`.
+    pub fn record_message(&mut self, address: &str, height: Option<u32>, now: i64) {
+        if let Some(peer) = self.connected.get_mut(address) {
+            peer.last_message = now;
+            if let Some(height) = height {
+                peer.best_height = peer.best_height.max(height);
+            }
+        }
+    }
+
+    /// The connected peer with the most blocks, if it has more than `height` and serves blocks at all.
+    pub fn best_peer(&self, height: u32) -> Option<&String> {
+        self.connected
+            .iter()
+            .filter(|(_, peer)| peer.version.services & NODE_NETWORK != 0)
+            .filter(|(_, peer)| peer.best_height > height)
+            .max_by_key(|(_, peer)| peer.best_height)
+            .map(|(address, _)| address)
+    }
+
+    /// Queues a message for a single peer. A peer that doesn't keep up with its queue misses messages
+    /// rather than holding up the node.
+    pub fn send(&self, address: &str, message: Message) {
+        if let Some(peer) = self.connected.get(address) {
+            if peer.outbox.try_send(message).is_err() {
+                eprintln!("Dropped a message for {}, its queue is full", address);
+            }
+        }
+    }
+
+    /// Queues a message for every peer we are connected to, except `except`.
+    pub fn broadcast(&self, message: &Message, except: Option<&str>) {
+        for address in self.connected.keys() {
+            if Some(address.as_str()) != except {
+                self.send(address, message.clone());
+            }
+        }
+    }
+
+    /// Picks addresses to open new outbound sessions with, until we have `MAX_OUTBOUND`, and takes note of them.
+    /// Skips the addresses we already keep sessions with, as well as `own_address` and the address we advertise,
+    /// either of which may have come back to us from peers.
+    pub fn select_outbound(&mut self, own_address: &str, now: i64) -> Vec<String> {
+        let mut exclude: Vec<String> = self.outbound.iter().cloned().collect();
+        exclude.push(own_address.to_string());
+        exclude.extend(self.listen_address.clone());
+        let wanted = MAX_OUTBOUND.saturating_sub(self.outbound.len());
+        let selected = self.address_book.select(wanted, now, &exclude);
+        self.outbound.extend(selected.iter().cloned());
+        selected
+    }
+
+    /// Frees the outbound slot of an address we gave up on.
+    pub fn outbound_ended(&mut self, address: &str) {
+        self.outbound.remove(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{PROTOCOL_VERSION, USER_AGENT};
+
+    fn version(best_height: u32) -> VersionInfo {
+        VersionInfo {
+            protocol_version: PROTOCOL_VERSION,
+            network: String::from("regtest"),
+            genesis_hash: String::from("00"),
+            best_height,
+            services: NODE_NETWORK,
+            user_agent: USER_AGENT.to_string(),
+            listen_address: None,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_sessions_and_broadcast() {
+        let mut peers = PeerManager::new();
+        let (alice, mut alice_inbox) = mpsc::channel(8);
+        let (bob, mut bob_inbox) = mpsc::channel(8);
+        peers.connect("10.0.0.1:8000", Direction::Outbound, version(3), alice, 0);
+        peers.connect(
+            "10.1.0.1:8000",
+            Direction::Inbound,
+            version(1),
+            bob.clone(),
+            0,
+        );
+        assert_eq!(
+            peers.peer("10.1.0.1:8000").unwrap().direction,
+            Direction::Inbound
+        );
+
+        // Broadcasts reach every connected peer but the one excluded
+        peers.broadcast(&Message::GetAddr, Some("10.0.0.1:8000"));
+        peers.broadcast(&Message::Verack, None);
+        assert!(matches!(alice_inbox.try_recv(), Ok(Message::Verack)));
+        assert!(matches!(bob_inbox.try_recv(), Ok(Message::GetAddr)));
+        assert!(matches!(bob_inbox.try_recv(), Ok(Message::Verack)));
+
+        // Peers are tracked as they announce blocks
+        assert_eq!(peers.best_peer(2).unwrap(), "10.0.0.1:8000");
+        peers.record_message("10.1.0.1:8000", Some(5), 10);
+        assert_eq!(peers.best_peer(2).unwrap(), "10.1.0.1:8000");
+        assert!(peers.best_peer(5).is_none());
+
+        // Peers that don't serve blocks aren't asked for them, however far ahead they are
+        let (carol, _) = mpsc::channel(8);
+        let mut light = version(10);
+        light.services = 0;
+        peers.connect("10.2.0.1:8000", Direction::Inbound, light, carol.clone(), 0);
+        assert_eq!(peers.best_peer(2).unwrap(), "10.1.0.1:8000");
+        peers.disconnect("10.2.0.1:8000", &carol);
+
+        // Only the session that is still registered can be disconnected
+        let (stale, _) = mpsc::channel(8);
+        assert!(peers.disconnect("10.1.0.1:8000", &stale).is_none());
+        assert!(peers.peer("10.1.0.1:8000").is_some());
+        let peer = peers.disconnect("10.1.0.1:8000", &bob).unwrap();
+        assert_eq!(peer.direction, Direction::Inbound);
+        assert!(peers.peer("10.1.0.1:8000").is_none());
+        assert_eq!(peers.connected.len(), 1);
+    }
+
+    #[test]
+    fn test_select_outbound() {
+        let mut peers = PeerManager::new();
+        for port in 0..MAX_OUTBOUND as u16 + 5 {
+            peers
+                .address_book_mut()
+                .add(&format!("10.0.{}.1:8000", port), 0);
+        }
+        peers.address_book_mut().add("127.0.0.1:8000", 0);
+
+        let selected = peers.select_outbound("127.0.0.1:8000", 0);
+        assert_eq!(selected.len(), MAX_OUTBOUND);
+        assert!(!selected.contains(&String::from("127.0.0.1:8000")));
+        // All slots are taken until one is given up on
+        assert!(peers.select_outbound("127.0.0.1:8000", 0).is_empty());
+        peers.outbound_ended(&selected[0]);
+        let replacement = peers.select_outbound("127.0.0.1:8000", 0);
+        assert_eq!(replacement.len(), 1);
+        assert!(!selected[1..].contains(&replacement[0]));
+
+        // Nor do we connect to the address we advertise, which peers may gossip back to us
+        let mut peers = PeerManager::new();
+        peers.set_listen_address(String::from("10.9.9.9:8000"));
+        peers.address_book_mut().add("10.9.9.9:8000", 0);
+        peers.address_book_mut().add("10.0.0.1:8000", 0);
+        assert_eq!(
+            peers.select_outbound("127.0.0.1:8000", 0),
+            vec![String::from("10.0.0.1:8000")]
+        );
+    }
+}
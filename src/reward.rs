@@ -0,0 +1,53 @@
+use crate::{
+    amount::{Amount, MAX_SUPPLY},
+    params::ChainParams,
+};
+
+/// New coins the block at `height` is scheduled to mint, halving every `halving_interval` blocks.
+pub fn block_subsidy(params: &ChainParams, height: u32) -> Amount {
+    let halvings = height / params.halving_interval.max(1);
+    if halvings >= u64::BITS {
+        return Amount::ZERO;
+    }
+    Amount::from_units(params.initial_subsidy.units() >> halvings)
+}
+
+/// New coins the block at `height` may actually mint once `issued` coins exist, so the total never exceeds `MAX_SUPPLY`.
+pub fn allowed_subsidy(params: &ChainParams, height: u32, issued: Amount) -> Amount {
+    let remaining = MAX_SUPPLY.checked_sub(issued).unwrap_or(Amount::ZERO);
+    block_subsidy(params, height).min(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsidy_halves() {
+        let params = ChainParams::mainnet();
+        assert_eq!(block_subsidy(&params, 1), params.initial_subsidy);
+        assert_eq!(
+            block_subsidy(&params, params.halving_interval - 1),
+            params.initial_subsidy
+        );
+        assert_eq!(
+            block_subsidy(&params, params.halving_interval),
+            Amount::from_units(params.initial_subsidy.units() / 2)
+        );
+        assert_eq!(
+            block_subsidy(&params, params.halving_interval * 64),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn test_subsidy_respects_max_supply() {
+        let params = ChainParams::mainnet();
+        let almost_all = MAX_SUPPLY.checked_sub(Amount::from_units(1)).unwrap();
+        assert_eq!(
+            allowed_subsidy(&params, 1, almost_all),
+            Amount::from_units(1)
+        );
+        assert_eq!(allowed_subsidy(&params, 1, MAX_SUPPLY), Amount::ZERO);
+    }
+}
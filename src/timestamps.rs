@@ -0,0 +1,158 @@
+// Rules for block timestamps. A block has to be later than the median of the blocks before it, which a single
+// miner with a wrong clock can't drag backwards, and may not be too far ahead of the time the network agrees on.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+use crate::block::Block;
+
+// Number of blocks whose timestamps make up the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// Peers whose clock we keep track of, the oldest making room for a new one, and how many we need before trusting
+// their offsets at all
+const MAX_TIME_SAMPLES: usize = 200;
+const MIN_TIME_SAMPLES: usize = 5;
+// Largest correction we apply to our own clock. A bigger offset means our clock or most peers are broken.
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`. A new block has to be later than this.
+pub fn median_time_past(chain: &[Block]) -> i64 {
+    let start = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<i64> = chain[start..]
+        .iter()
+        .map(|block| block.header.timestamp)
+        .collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+}
+
+// Our estimate of the network's clock: the local clock corrected by the median offset of the peers' clocks.
+// Samples are kept per IP address, so a node can't outvote the others by connecting from many ports.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkTime {
+    offsets: HashMap<String, i64>, // Seconds each peer's clock is ahead of ours, one sample per IP address
+    order: VecDeque<String>,       // IP addresses of the samples, oldest first
+}
+
+// What a sample from the peer at `address` is kept under: its IP address, or the whole address if it has none
+fn sample_key(address: &str) -> String {
+    match address.parse::<SocketAddr>() {
+        Ok(address) => address.ip().to_string(),
+        Err(_) => address.to_string(),
+    }
+}
+
+impl NetworkTime {
+    pub fn new() -> Self {
+        NetworkTime::default()
+    }
+
+    /// Records that `peer` reported `peer_time` when our clock said `now`, replacing an earlier sample from its IP.
+    pub fn add_sample(&mut self, peer: &str, peer_time: i64, now: i64) {
+        let key = sample_key(peer);
+        self.order.retain(|other| *other != key);
+        if self.order.len() >= MAX_TIME_SAMPLES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.offsets.remove(&oldest);
+            }
+        }
+        self.offsets.insert(key.clone(), peer_time - now);
+        self.order.push_back(key);
+    }
+
+    /// Forgets the sample from the IP of `peer`, once we are no longer connected to it.
+    pub fn remove_sample(&mut self, peer: &str) {
+        let key = sample_key(peer);
+        self.order.retain(|other| *other != key);
+        self.offsets.remove(&key);
+    }
+
+    /// Median offset of the peers' clocks, or zero while there are too few peers or they disagree too much with us.
+    pub fn offset(&self) -> i64 {
+        if self.offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_TIME_ADJUSTMENT {
+            return 0;
+        }
+        median
+    }
+
+    /// The network-adjusted time when our clock says `now`.
+    pub fn adjusted_time(&self, now: i64) -> i64 {
+        now + self.offset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), 0);
+        let chain: Vec<Block> = [5, 1, 4, 2, 3]
+            .iter()
+            .map(|&timestamp| Block::new(0, timestamp, 0, String::from("0"), vec![]))
+            .collect();
+        assert_eq!(median_time_past(&chain), 3);
+
+        // Only the last blocks count
+        let chain: Vec<Block> = (0..20)
+            .map(|timestamp| Block::new(0, timestamp, 0, String::from("0"), vec![]))
+            .collect();
+        assert_eq!(median_time_past(&chain), 14);
+    }
+
+    #[test]
+    fn test_network_time() {
+        let mut time = NetworkTime::new();
+        for peer in [
+            "10.0.0.1:8000",
+            "10.0.0.2:8000",
+            "10.0.0.3:8000",
+            "10.0.0.4:8000",
+        ] {
+            time.add_sample(peer, 1_060, 1_000);
+        }
+        // Too few peers to go by
+        assert_eq!(time.adjusted_time(1_000), 1_000);
+
+        time.add_sample("10.0.0.5:8000", 0, 1_000);
+        assert_eq!(time.offset(), 60);
+        assert_eq!(time.adjusted_time(1_000), 1_060);
+
+        // A peer only has one say, however often it reports and from however many ports
+        for port in 8000..8010 {
+            time.add_sample(&format!("10.0.0.5:{}", port), 100_000, 1_000);
+        }
+        assert_eq!(time.offset(), 60);
+
+        // Peers we disconnected from have no say at all
+        time.remove_sample("10.0.0.1:8001");
+        assert_eq!(time.offset(), 0);
+
+        // Once full, the oldest samples make room for new ones rather than new peers being ignored
+        let mut time = NetworkTime::new();
+        for peer in 0..MAX_TIME_SAMPLES {
+            time.add_sample(&format!("10.0.{}.{}:8000", peer / 256, peer % 256), 0, 0);
+        }
+        for peer in 0..MAX_TIME_SAMPLES {
+            time.add_sample(&format!("10.1.{}.{}:8000", peer / 256, peer % 256), 60, 0);
+        }
+        assert_eq!(time.offset(), 60);
+
+        // Offsets beyond what we're willing to correct are ignored
+        let mut time = NetworkTime::new();
+        for peer in ["a", "b", "c", "d", "e"] {
+            time.add_sample(peer, MAX_TIME_ADJUSTMENT + 1, 0);
+        }
+        assert_eq!(time.offset(), 0);
+    }
+}
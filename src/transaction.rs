@@ -1,28 +1,290 @@
+use secp256k1::{ecdsa::Signature, Message, PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    address::{Address, SECP},
+    amount::Amount,
+    encoding::{encode, Encode, Encoder, ENCODING_VERSION},
+    utxo::{OutPoint, TxOutput},
+};
+
+// Domain separator so a transaction digest can never collide with any other signed payload
+const TX_DIGEST_TAG: &[u8] = b"blockchain/tx/v1";
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
     // Define the fields of a transaction here
-    pub sender: String,
-    pub receiver: String,
-    pub amount: f64,
-    // Add any other fields that make sense for your use case
+    pub sender: Address,
+    pub receiver: Address,
+    pub amount: Amount,
+    pub fee: Amount, // Paid to the miner on top of `amount`, equal to inputs minus outputs in the UTXO ledger
+    pub nonce: u64,  // Sequence number of this transaction among all transactions sent by `sender`
+    pub inputs: Vec<OutPoint>, // Outputs owned by `sender` that this transaction spends, UTXO ledger only
+    pub change: Amount,        // Paid back to `sender` as a second output, UTXO ledger only
+    pub public_key: String,    // Hex-encoded compressed secp256k1 public key of the sender
+    pub signature: String,     // Hex-encoded compact ECDSA signature over `digest()`
 }
 
 impl Transaction {
+    // Create a new, unsigned transaction
+    pub fn new(
+        sender: Address,
+        receiver: Address,
+        amount: Amount,
+        fee: Amount,
+        nonce: u64,
+    ) -> Self {
+        Transaction {
+            sender,
+            receiver,
+            amount,
+            fee,
+            nonce,
+            inputs: Vec::new(),
+            change: Amount::ZERO,
+            public_key: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    // Create a new, unsigned transaction for the UTXO ledger that spends `inputs`
+    pub fn spend(
+        sender: Address,
+        inputs: Vec<OutPoint>,
+        receiver: Address,
+        amount: Amount,
+        change: Amount,
+        fee: Amount,
+    ) -> Self {
+        Transaction {
+            inputs,
+            change,
+            ..Transaction::new(sender, receiver, amount, fee, 0)
+        }
+    }
+
+    // Create the coinbase transaction of the block at `height`, minting `amount` to the miner.
+    // The height goes into the nonce so every coinbase has a distinct hash.
+    pub fn coinbase(miner: Address, amount: Amount, height: u32) -> Self {
+        Transaction::new(Address::NULL, miner, amount, Amount::ZERO, height as u64)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == Address::NULL
+    }
+
+    /// What the transaction takes from the sender's account: the amount plus the fee. `None` on overflow.
+    pub fn cost(&self) -> Option<Amount> {
+        self.amount.checked_add(self.fee)
+    }
+
+    /// Outputs created by the transaction: the payment to `receiver`, then the change back to `sender` if there is any.
+    pub fn outputs(&self) -> Vec<TxOutput> {
+        let mut outputs = vec![TxOutput {
+            address: self.receiver,
+            amount: self.amount,
+        }];
+        if !self.change.is_zero() {
+            outputs.push(TxOutput {
+                address: self.sender,
+                amount: self.change,
+            });
+        }
+        outputs
+    }
+
+    // Encodes every field except the signature
+    fn encode_unsigned(&self, encoder: &mut Encoder) {
+        encoder.fixed(self.sender.as_bytes());
+        encoder.fixed(self.receiver.as_bytes());
+        encoder.u64(self.amount.units());
+        encoder.u64(self.fee.units());
+        encoder.u64(self.nonce);
+        encoder.list(&self.inputs);
+        encoder.u64(self.change.units());
+        encoder.str(&self.public_key);
+    }
+
+    /// Digest of the transaction contents that the signature commits to: its canonical encoding without the signature.
+    pub fn digest(&self) -> [u8; 32] {
+        let mut encoder = Encoder::new();
+        encoder.u8(ENCODING_VERSION);
+        self.encode_unsigned(&mut encoder);
+        let mut hasher = Sha256::new();
+        hasher.update(TX_DIGEST_TAG);
+        hasher.update(encoder.into_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Size of the canonical encoding in bytes, used for block limits and to rank transactions by fee rate.
+    pub fn size(&self) -> usize {
+        encode(self).len()
+    }
+
+    /// Identifier of the transaction: hex encoded SHA-256 of its canonical encoding, signature included.
+    pub fn hash(&self) -> String {
+        hex::encode(Sha256::digest(&encode(self)))
+    }
+
+    /// Signs the transaction with the sender's secret key, filling in `public_key` and `signature`.
+    pub fn sign(&mut self, secret_key: &SecretKey) {
+        let public_key = PublicKey::from_secret_key(&SECP, secret_key);
+        self.public_key = hex::encode(public_key.serialize());
+        let message = Message::from_slice(&self.digest()).expect("digest is 32 bytes");
+        let signature = SECP.sign_ecdsa(&message, secret_key);
+        self.signature = hex::encode(signature.serialize_compact());
+    }
+
     pub fn verify(&self) -> bool {
-        // Check if amount is positive
-        if self.amount <= 0.0 {
+        // Check if amount is positive and not larger than could ever exist
+        if self.amount.is_zero()
+            || !self.amount.is_valid()
+            || !self.fee.is_valid()
+            || !self.change.is_valid()
+        {
             return false;
         }
 
-        // Check if sender and receiver are not empty and are different
-        if self.sender.is_empty() || self.receiver.is_empty() || self.sender == self.receiver {
+        // Check if sender and receiver are different
+        if self.sender == self.receiver {
             return false;
         }
 
-        // TODO: Add more checks if needed.
+        // The sender address must be the one derived from the signing key
+        let public_key = match parse_public_key(&self.public_key) {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        if Address::from_public_key(&public_key) != self.sender {
+            return false;
+        }
+
+        // And the signature must be valid for this exact transaction
+        let signature = match hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_compact(&bytes).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+        // The hash covers the key and signature as written, so they must be written the one way `sign` does.
+        // Otherwise anyone could relay the transaction under another hash, e.g. with its hex in upper case.
+        if self.public_key != hex::encode(public_key.serialize())
+            || self.signature != hex::encode(signature.serialize_compact())
+        {
+            return false;
+        }
+        let message = Message::from_slice(&self.digest()).expect("digest is 32 bytes");
+        SECP.verify_ecdsa(&message, &signature, &public_key).is_ok()
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        self.encode_unsigned(encoder);
+        encoder.str(&self.signature);
+    }
+}
+
+fn parse_public_key(hex_key: &str) -> Option<PublicKey> {
+    let bytes = hex::decode(hex_key).ok()?;
+    PublicKey::from_slice(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    fn key(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn signed_transaction(
+        secret_key: &SecretKey,
+        receiver: Address,
+        amount: Amount,
+    ) -> Transaction {
+        let mut transaction = Transaction::new(
+            Address::from_secret_key(secret_key),
+            receiver,
+            amount,
+            Amount::ZERO,
+            0,
+        );
+        transaction.sign(secret_key);
+        transaction
+    }
+
+    #[test]
+    fn test_signed_transaction_verifies() {
+        let alice = key(1);
+        let bob = Address::from_secret_key(&key(2));
+        assert!(signed_transaction(&alice, bob, coins(10)).verify());
+    }
+
+    #[test]
+    fn test_tampered_transaction_fails() {
+        let alice = key(1);
+        let bob = Address::from_secret_key(&key(2));
+        let mut transaction = signed_transaction(&alice, bob, coins(10));
+        transaction.amount = coins(1000);
+        assert!(!transaction.verify());
+        transaction.amount = coins(10);
+        transaction.nonce = 1;
+        assert!(!transaction.verify());
+        transaction.nonce = 0;
+        transaction.fee = coins(1);
+        assert!(!transaction.verify());
+
+        let unsigned = Transaction::new(
+            Address::from_secret_key(&alice),
+            transaction.receiver,
+            coins(1),
+            Amount::ZERO,
+            0,
+        );
+        assert!(!unsigned.verify());
+    }
+
+    #[test]
+    fn test_sender_must_own_key() {
+        // Mallory signs a transaction spending from Alice's address with her own key
+        let alice = Address::from_secret_key(&key(1));
+        let mallory = key(3);
+        let mut transaction = Transaction::new(
+            alice,
+            Address::from_secret_key(&mallory),
+            coins(5),
+            Amount::ZERO,
+            0,
+        );
+        transaction.sign(&mallory);
+        assert!(!transaction.verify());
+    }
+
+    #[test]
+    fn test_key_and_signature_are_canonical() {
+        // Another spelling of the same key or signature would give the transaction another hash
+        let alice = key(1);
+        let bob = Address::from_secret_key(&key(2));
+        let transaction = signed_transaction(&alice, bob, coins(10));
+
+        let mut upper_case = transaction.clone();
+        upper_case.signature = upper_case.signature.to_uppercase();
+        assert_ne!(upper_case.hash(), transaction.hash());
+        assert!(!upper_case.verify());
+        let mut upper_case = transaction.clone();
+        upper_case.public_key = upper_case.public_key.to_uppercase();
+        assert!(!upper_case.verify());
 
-        true
+        let mut uncompressed = transaction.clone();
+        let public_key = PublicKey::from_secret_key(&SECP, &alice);
+        uncompressed.public_key = hex::encode(public_key.serialize_uncompressed());
+        assert!(!uncompressed.verify());
     }
 }
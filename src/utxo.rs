@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    address::Address,
+    amount::Amount,
+    block::Block,
+    encoding::{Encode, Encoder},
+    transaction::Transaction,
+};
+
+// How balances are tracked by a Blockchain, chosen when it is created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LedgerMode {
+    // Balances are the sum of all transfers in and out of an address, ordered by per-account nonces
+    #[default]
+    Account,
+    // Transactions spend unspent outputs of earlier transactions and create new ones
+    Utxo,
+}
+
+impl LedgerMode {
+    /// The mode by the name it is chosen with on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "account" => Some(LedgerMode::Account),
+            "utxo" => Some(LedgerMode::Utxo),
+            _ => None,
+        }
+    }
+}
+
+// Reference to a single output of an earlier transaction
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u32,
+}
+
+impl Encode for OutPoint {
+    fn encode_to(&self, encoder: &mut Encoder) {
+        encoder.str(&self.txid);
+        encoder.u32(self.vout);
+    }
+}
+
+// A coin created by a transaction, spendable by whoever owns `address`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxOutput {
+    pub address: Address,
+    pub amount: Amount,
+}
+
+// The set of all outputs that have been created but not yet spent.
+// Serialized as a list of entries since the keys are not strings.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(OutPoint, TxOutput)>", into = "Vec<(OutPoint, TxOutput)>")]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        UtxoSet::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.outputs.get(outpoint)
+    }
+
+    pub fn is_spendable(&self, outpoint: &OutPoint) -> bool {
+        self.outputs.contains_key(outpoint)
+    }
+
+    /// Sum of every unspent output owned by an address, `None` on overflow.
+    pub fn balance(&self, address: &Address) -> Option<Amount> {
+        Amount::checked_sum(
+            self.outputs
+                .values()
+                .filter(|output| &output.address == address)
+                .map(|output| output.amount),
+        )
+    }
+
+    /// Spends the inputs of a transaction and adds its outputs. The transaction must already be validated.
+    /// Returns the spent outputs, which `undo_block` needs to bring them back.
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Vec<(OutPoint, TxOutput)> {
+        let spent = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.outputs.remove_entry(input))
+            .collect();
+        let txid = transaction.hash();
+        for (vout, output) in transaction.outputs().into_iter().enumerate() {
+            let outpoint = OutPoint {
+                txid: txid.clone(),
+                vout: vout as u32,
+            };
+            self.outputs.insert(outpoint, output);
+        }
+        spent
+    }
+
+    pub fn apply_block(&mut self, block: &Block) -> Vec<(OutPoint, TxOutput)> {
+        block
+            .transactions
+            .iter()
+            .flat_map(|transaction| self.apply_transaction(transaction))
+            .collect()
+    }
+
+    /// Reverts `apply_block` for the last block applied, given the outputs it spent.
+    pub fn undo_block(&mut self, block: &Block, spent: Vec<(OutPoint, TxOutput)>) {
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            for vout in 0..transaction.outputs().len() {
+                self.outputs.remove(&OutPoint {
+                    txid: txid.clone(),
+                    vout: vout as u32,
+                });
+            }
+        }
+        self.outputs.extend(spent);
+    }
+}
+
+impl From<Vec<(OutPoint, TxOutput)>> for UtxoSet {
+    fn from(entries: Vec<(OutPoint, TxOutput)>) -> Self {
+        UtxoSet {
+            outputs: entries.into_iter().collect(),
+        }
+    }
+}
+
+impl From<UtxoSet> for Vec<(OutPoint, TxOutput)> {
+    fn from(utxos: UtxoSet) -> Self {
+        utxos.outputs.into_iter().collect()
+    }
+}
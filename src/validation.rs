@@ -0,0 +1,72 @@
+use std::fmt;
+
+use crate::block::Block;
+
+// The consensus rules a block has to follow, in the order they are checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationRule {
+    Genesis,      // The chain starts with our genesis block
+    Known,        // The block isn't in the block tree already
+    Index,        // The index is one more than the parent's
+    PreviousHash, // The block links to the hash of its parent
+    ForkDepth,    // A block off the active chain isn't too far behind its tip
+    Hash,         // The stored hash is the hash of the header
+    MerkleRoot,   // The header commits to the transactions in the body
+    Size,         // The body is within the block size limits
+    ProofOfWork,  // The header has the expected target for its height and the hash meets it
+    Timestamp,    // The timestamp is within the allowed range
+    Coinbase, // The block starts with its only coinbase, which doesn't pay out more than allowed
+    Transactions, // Every other transaction is valid and affordable given the blocks before it
+}
+
+impl ValidationRule {
+    pub fn message(self) -> &'static str {
+        match self {
+            ValidationRule::Genesis => "Chain doesn't start with the genesis block",
+            ValidationRule::Known => "Block already known",
+            ValidationRule::Index => "Block index doesn't follow its parent",
+            ValidationRule::PreviousHash => "Block doesn't link to its parent",
+            ValidationRule::ForkDepth => "Block is too far behind the active chain",
+            ValidationRule::Hash => "Block hash doesn't match its header",
+            ValidationRule::MerkleRoot => "Merkle root doesn't match the transactions",
+            ValidationRule::Size => "Block exceeds the size limits",
+            ValidationRule::ProofOfWork => "Block did not meet difficulty requirement",
+            ValidationRule::Timestamp => "Block timestamp out of range",
+            ValidationRule::Coinbase => "Invalid coinbase",
+            ValidationRule::Transactions => "Invalid transactions",
+        }
+    }
+}
+
+impl fmt::Display for ValidationRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+// Why a chain is invalid: the first offending block and the rule it breaks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub index: usize,
+    pub hash: String,
+    pub rule: ValidationRule,
+}
+
+impl ValidationError {
+    /// The error for `block` breaking `rule`.
+    pub fn new(block: &Block, rule: ValidationRule) -> Self {
+        ValidationError {
+            index: block.header.index as usize,
+            hash: block.hash.clone(),
+            rule,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block {} ({}): {}", self.index, self.hash, self.rule)
+    }
+}
+
+impl std::error::Error for ValidationError {}